@@ -0,0 +1,64 @@
+/// At this bit depth the quantization is skipped entirely, so the bitcrusher is transparent.
+pub const MAX_BIT_DEPTH: f32 = 16.0;
+
+/// A lo-fi bit depth and sample rate reducer.
+#[derive(Default)]
+pub struct Bitcrusher {
+    /// The input sample currently being held by the sample rate reduction.
+    held_sample: f32,
+    /// Advances by `1 / downsample` every sample. A new input sample is taken whenever it wraps.
+    hold_phase: f32,
+}
+
+impl Bitcrusher {
+    pub fn reset(&mut self) {
+        self.held_sample = 0.0;
+        self.hold_phase = 0.0;
+    }
+
+    /// Whether the sample being held is silent, so the bitcrusher would only output silence for a
+    /// silent input.
+    pub fn is_silent(&self) -> bool {
+        self.held_sample == 0.0
+    }
+
+    /// Quantize the input to `bits` bits and hold every sample for `downsample` samples. Both may
+    /// be fractional, so the effect can be swept smoothly.
+    pub fn process(&mut self, input: f32, bits: f32, downsample: f32) -> f32 {
+        self.hold_phase += 1.0 / downsample.max(1.0);
+        if self.hold_phase >= 1.0 {
+            self.hold_phase -= 1.0;
+            self.held_sample = input;
+        }
+
+        if bits >= MAX_BIT_DEPTH {
+            return self.held_sample;
+        }
+
+        // The signal is bipolar, so half of the levels are used for either polarity. Like integer
+        // PCM the top level is dropped to make room for zero, which leaves `2^bits` levels.
+        let steps = 2.0f32.powf(bits.max(1.0) - 1.0);
+        (self.held_sample * steps)
+            .round()
+            .clamp(-steps, steps - 1.0)
+            / steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_depth_sets_the_number_of_levels() {
+        for bits in 1..=8 {
+            let mut bitcrusher = Bitcrusher::default();
+            let mut levels: Vec<f32> = (-1000..=1000)
+                .map(|x| bitcrusher.process(x as f32 / 1000.0, bits as f32, 1.0))
+                .collect();
+            levels.dedup();
+
+            assert_eq!(levels.len(), 1 << bits, "{bits} bits");
+        }
+    }
+}
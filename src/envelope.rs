@@ -1,106 +1,375 @@
+use nih_plug::prelude::Enum;
+
+/// The shortest release in samples, no matter what the release time is set to. Dropping straight
+/// from the sustain level to silence would click, so this always leaves a short fade out.
+const MIN_RELEASE_SAMPLES: f32 = 32.0;
+/// The exponent the stage progress is shaped with at the strongest curve.
+const MAX_CURVE_EXPONENT: f32 = 5.0;
+
 pub struct ADSR {
     /// The attack time in seconds
     pub attack: f32,
+    /// How long the envelope stays at its peak after the attack, in seconds
+    pub hold: f32,
     /// The decay time in seconds
     pub decay: f32,
+    /// The time in seconds of a second decay stage between the decay and the sustain. With a
+    /// second decay the first one only decays to `break_level`. Zero skips the second decay.
+    pub decay_2: f32,
+    /// The level in the range [0, 1] the first decay stage ends at when there's a second one
+    pub break_level: f32,
     /// The sustain level in the range [0, 1]
     pub sustain: f32,
     /// The release time in seconds
     pub release: f32,
+    /// Multiplies the release time, so it can be adjusted per note without touching `release`
+    pub release_scale: f32,
+    /// How far the attack overshoots past 1.0 before the decay stage brings it back down
+    pub punch: f32,
+    /// The shape of the attack, decay and release ramps in the range [0, 1], from linear to
+    /// strongly exponential
+    pub curve: f32,
+
+    /// Whether the envelope follows the note or plays through as a one-shot
+    pub gate_mode: GateMode,
+    /// Whether the envelope plays once or keeps cycling while the note is held
+    pub mode: EnvelopeMode,
+    /// Set once the note has been released, which stops a looping envelope from starting another
+    /// cycle
+    pub note_released: bool,
 
     pub stage: EnvelopeStage,
     pub timer: f32,
+    /// The last value returned by `next()`
+    pub current_level: f32,
+    /// The level the attack, decay or release stage ramps from. Retriggering picks up from wherever
+    /// the envelope currently is, instead of jumping back to silence, the peak or the sustain
+    /// level.
+    pub start_level: f32,
 }
 
 impl Default for ADSR {
     fn default() -> Self {
         Self {
             attack: 0.05,
+            hold: 0.0,
             decay: 0.001,
+            decay_2: 0.0,
+            break_level: 0.5,
             sustain: 0.8,
             release: 0.1,
+            release_scale: 1.0,
+            punch: 0.0,
+            curve: 0.0,
+
+            gate_mode: GateMode::Gate,
+            mode: EnvelopeMode::OneShot,
+            note_released: false,
 
             stage: EnvelopeStage::Attack,
             timer: 0.0,
+            current_level: 0.0,
+            start_level: 0.0,
         }
     }
 }
 
 impl ADSR {
+    /// Silence the envelope and clear its per-note state, so the first `next()` after a reset
+    /// returns exactly 0. This goes straight to the idle stage, since finishing a release would
+    /// start another cycle in the loop modes.
     pub fn reset(&mut self) {
-        self.stage = EnvelopeStage::Release;
-        self.timer = self.release;
+        self.stage = EnvelopeStage::Idle;
+        self.timer = 0.0;
+        self.release_scale = 1.0;
+        self.current_level = 0.0;
+        self.start_level = 0.0;
+        self.note_released = false;
     }
 
     pub fn trigger(&mut self, event: EnvelopeEvent) {
+        self.note_released = matches!(event, EnvelopeEvent::Release);
+
+        // One-shot envelopes release on their own once the decay stage is done
+        if matches!(event, EnvelopeEvent::Release) && self.gate_mode == GateMode::Trigger {
+            return;
+        }
+
         self.timer = 0.0;
+        self.start_level = self.current_level;
         match event {
             EnvelopeEvent::Attack => {
                 self.stage = EnvelopeStage::Attack;
             }
+            EnvelopeEvent::Decay => {
+                self.stage = EnvelopeStage::Decay;
+            }
             EnvelopeEvent::Release => {
                 self.stage = EnvelopeStage::Release;
             }
         }
     }
 
+    /// Retrigger the envelope for a new note. An envelope that's still in its hold, decay or
+    /// sustain stage restarts that stage from its current level instead of going back to the
+    /// attack, so legato notes don't dip and swell again. Otherwise the envelope restarts from `target`, though a note played
+    /// from silence always gets the attack.
+    pub fn retrigger(&mut self, target: RetriggerStage) {
+        match (self.stage, target) {
+            (EnvelopeStage::Idle, _)
+            | (EnvelopeStage::Attack | EnvelopeStage::Release, RetriggerStage::Attack) => {
+                self.trigger(EnvelopeEvent::Attack)
+            }
+            (EnvelopeStage::Attack | EnvelopeStage::Release, RetriggerStage::Decay) => {
+                self.trigger(EnvelopeEvent::Decay)
+            }
+            (
+                EnvelopeStage::Hold
+                | EnvelopeStage::Decay
+                | EnvelopeStage::SecondDecay
+                | EnvelopeStage::Sustain,
+                _,
+            ) => {
+                self.timer = 0.0;
+                self.start_level = self.current_level;
+            }
+        }
+    }
+
+    /// Render the envelope's full curve for a note that's held for `gate_samples` samples, out to
+    /// `total_samples` samples. This retriggers the envelope, so its current state is lost. Meant
+    /// for checking the envelope's shape outside of the plugin, not for use on the audio thread.
+    pub fn render(
+        &mut self,
+        sample_rate: f32,
+        gate_samples: usize,
+        total_samples: usize,
+    ) -> Vec<f32> {
+        self.trigger(EnvelopeEvent::Attack);
+
+        (0..total_samples)
+            .map(|i| {
+                if i == gate_samples {
+                    self.trigger(EnvelopeEvent::Release);
+                }
+
+                self.next(sample_rate)
+            })
+            .collect()
+    }
+
+    /// Whether the release has run its course and the envelope only outputs silence.
+    pub fn is_finished(&self) -> bool {
+        self.stage == EnvelopeStage::Idle
+    }
+
     pub fn next(&mut self, sample_rate: f32) -> f32 {
+        self.current_level = self.next_level(sample_rate);
+        self.current_level
+    }
+
+    /// Every stage advances its timer first and then either ramps towards its target or, once the
+    /// timer reaches the stage's length, outputs exactly that target and moves on. That way each
+    /// stage's last sample lands on its target and the next stage picks up one step later, instead
+    /// of the target being emitted a sample late and then repeated.
+    fn next_level(&mut self, sample_rate: f32) -> f32 {
+        let step = 1.0 / sample_rate;
+
         match self.stage {
             EnvelopeStage::Attack => {
+                self.timer += step;
                 if self.timer >= self.attack {
-                    self.stage = EnvelopeStage::Decay;
+                    self.stage = if self.hold > 0.0 {
+                        EnvelopeStage::Hold
+                    } else {
+                        EnvelopeStage::Decay
+                    };
                     self.timer = 0.0;
+                    self.start_level = 1.0 + self.punch;
 
-                    return 1.0;
+                    return 1.0 + self.punch;
                 }
 
-                self.timer += 1.0 / sample_rate;
-
-                let x = interpolate(self.timer / self.attack, 0.0, 1.0);
+                let x = interpolate(
+                    shape(self.timer / self.attack, self.curve),
+                    self.start_level,
+                    1.0 + self.punch,
+                );
 
                 return x;
             }
+            EnvelopeStage::Hold => {
+                self.timer += step;
+                if self.timer >= self.hold {
+                    self.stage = EnvelopeStage::Decay;
+                    self.timer = 0.0;
+                    self.start_level = 1.0 + self.punch;
+                }
+
+                1.0 + self.punch
+            }
             EnvelopeStage::Decay => {
+                let has_second_decay = self.decay_2 > 0.0;
+                let target = if has_second_decay {
+                    self.break_level
+                } else {
+                    self.sustain
+                };
+
+                self.timer += step;
                 if self.timer >= self.decay {
-                    self.stage = EnvelopeStage::Sustain;
-                    self.timer = 0.0;
+                    if has_second_decay {
+                        self.stage = EnvelopeStage::SecondDecay;
+                        self.timer = 0.0;
+                        self.start_level = self.break_level;
 
-                    return self.sustain;
+                        return self.break_level;
+                    }
+
+                    return self.finish_decay();
                 }
 
-                self.timer += 1.0 / sample_rate;
+                let x = interpolate(
+                    shape(self.timer / self.decay, self.curve),
+                    self.start_level,
+                    target,
+                );
+
+                return x;
+            }
+            EnvelopeStage::SecondDecay => {
+                self.timer += step;
+                if self.timer >= self.decay_2 {
+                    return self.finish_decay();
+                }
 
-                // Linear step from
-                let x = interpolate(self.timer / self.decay, 1.0, self.sustain);
+                let x = interpolate(
+                    shape(self.timer / self.decay_2, self.curve),
+                    self.start_level,
+                    self.sustain,
+                );
 
                 return x;
             }
-            EnvelopeStage::Sustain => self.sustain,
+            EnvelopeStage::Sustain => {
+                // The mode can be switched to trigger while a note is being held
+                if self.gate_mode == GateMode::Trigger {
+                    self.stage = EnvelopeStage::Release;
+                    self.timer = 0.0;
+                    self.start_level = self.sustain;
+                }
+
+                self.sustain
+            }
             EnvelopeStage::Release => {
-                if self.timer >= self.release {
+                let release =
+                    (self.release * self.release_scale).max(MIN_RELEASE_SAMPLES / sample_rate);
+
+                self.timer += step;
+                if self.timer >= release {
+                    self.stage = if self.mode == EnvelopeMode::LoopADSR && !self.note_released {
+                        EnvelopeStage::Attack
+                    } else {
+                        EnvelopeStage::Idle
+                    };
+                    self.timer = 0.0;
+                    self.start_level = 0.0;
+
                     return 0.0;
                 }
 
-                self.timer += 1.0 / sample_rate;
-
-                let x = interpolate(self.timer / self.release, self.sustain, 0.0);
+                let x = interpolate(
+                    shape(self.timer / release, self.curve),
+                    self.start_level,
+                    0.0,
+                );
                 return x;
             }
+            EnvelopeStage::Idle => 0.0,
         }
     }
+
+    /// Move on from the last decay stage once it has reached the sustain level, and return that
+    /// level.
+    fn finish_decay(&mut self) -> f32 {
+        self.stage = match (self.mode, self.gate_mode) {
+            (EnvelopeMode::LoopAD, _) if !self.note_released => EnvelopeStage::Attack,
+            (EnvelopeMode::LoopADSR, _) if !self.note_released => EnvelopeStage::Release,
+            (_, GateMode::Gate) => EnvelopeStage::Sustain,
+            (_, GateMode::Trigger) => EnvelopeStage::Release,
+        };
+        self.timer = 0.0;
+        self.start_level = self.sustain;
+
+        self.sustain
+    }
 }
 
 pub enum EnvelopeEvent {
     Attack,
+    /// Skip the attack and decay from the current level towards the sustain level.
+    Decay,
     Release,
 }
 
+/// Where the envelope restarts when a new note arrives while it's still attacking or releasing.
+#[derive(Enum, Clone, Copy, PartialEq)]
+pub enum RetriggerStage {
+    Attack,
+    /// Skips the attack, so repeated notes keep their level and only the decay is heard again.
+    Decay,
+}
+
+#[derive(Enum, Clone, Copy, PartialEq)]
+pub enum GateMode {
+    /// Sustain for as long as the note is held, and release on note off.
+    Gate,
+    /// Ignore note off and play the attack and decay stages followed by the release as a
+    /// one-shot. Useful for drums and other percussive sounds.
+    Trigger,
+}
+
+/// Whether the envelope plays through once, or cycles on its own like an LFO. A looping envelope
+/// stops looping once its note is released and finishes the usual way.
+#[derive(Enum, Clone, Copy, PartialEq)]
+pub enum EnvelopeMode {
+    #[name = "One Shot"]
+    OneShot,
+    /// Restart the attack as soon as the decay reaches the sustain level.
+    #[name = "Loop AD"]
+    LoopAD,
+    /// Go straight from the decay into the release, and restart the attack once that's done.
+    #[name = "Loop ADSR"]
+    LoopADSR,
+}
+
+/// Each stage is its own variant instead of an entry in a list of generic segments, since nearly
+/// every stage has some behavior of its own (the hold, punch's overshoot, the loop modes and
+/// retriggering). Another decay stage would be added the same way `SecondDecay` was.
 #[derive(Clone, Copy, PartialEq)]
 pub enum EnvelopeStage {
     Attack,
+    /// Stays at the attack's peak for the hold time before decaying.
+    Hold,
     Decay,
+    /// Decays from the break level to the sustain level, if the envelope has a second decay.
+    SecondDecay,
     Sustain,
     Release,
+    /// The release has finished and the envelope only outputs silence until it's triggered again.
+    Idle,
+}
+
+/// Shape a stage's progress in [0, 1] so the stage moves quickly at first and then eases into its
+/// target, like an analog envelope charging or discharging a capacitor. A curve of 0 leaves the
+/// progress untouched.
+fn shape(progress: f32, curve: f32) -> f32 {
+    if curve <= 0.0 {
+        return progress;
+    }
+
+    let exponent = 1.0 + curve * (MAX_CURVE_EXPONENT - 1.0);
+    1.0 - (1.0 - progress.min(1.0)).powf(exponent)
 }
 
 /// Linear interpolation between two values
@@ -109,3 +378,182 @@ pub enum EnvelopeStage {
 fn interpolate(value: f32, from: f32, to: f32) -> f32 {
     return from * (1.0 - value) + to * value;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    fn test_envelope() -> ADSR {
+        ADSR {
+            attack: 0.01,
+            decay: 0.05,
+            sustain: 0.5,
+            release: 0.1,
+            ..ADSR::default()
+        }
+    }
+
+    #[test]
+    fn trigger_mode_ignores_note_off() {
+        let mut envelope = ADSR {
+            gate_mode: GateMode::Trigger,
+            ..test_envelope()
+        };
+        let total_samples = SAMPLE_RATE as usize;
+        let released_early = envelope.render(SAMPLE_RATE, 10, total_samples);
+        let held = envelope.render(SAMPLE_RATE, total_samples, total_samples);
+
+        assert_eq!(released_early, held);
+        // It still plays through the decay and the release on its own
+        let sounding_samples = held.iter().filter(|&&x| x > 0.0).count() as f32;
+        assert!((sounding_samples / SAMPLE_RATE - 0.16).abs() < 0.001);
+        assert!(envelope.is_finished());
+    }
+
+    #[test]
+    fn render_hits_the_peak_sustain_and_release_timing() {
+        let mut envelope = test_envelope();
+        let gate_samples = SAMPLE_RATE as usize / 2;
+        let output = envelope.render(SAMPLE_RATE, gate_samples, SAMPLE_RATE as usize);
+
+        // The attack ends on its 480th sample, right at the peak
+        let peak_index = (0.01 * SAMPLE_RATE) as usize - 1;
+        assert_eq!(output[peak_index], 1.0);
+        assert!(output[..peak_index].iter().all(|&x| x < 1.0));
+        assert!(output.iter().all(|&x| x <= 1.0));
+
+        assert_eq!(output[(0.1 * SAMPLE_RATE) as usize], 0.5);
+        assert_eq!(output[gate_samples - 1], 0.5);
+
+        // The release starts from the sustain level and reaches zero 100 ms after the note off
+        assert!(output[gate_samples] < 0.5 && output[gate_samples] > 0.49);
+        let silent_from = output.iter().rposition(|&x| x > 0.0).unwrap() + 1;
+        let release_time = (silent_from - gate_samples) as f32 / SAMPLE_RATE;
+        assert!((release_time - 0.1).abs() < 2.0 / SAMPLE_RATE);
+        assert!(output[silent_from..].iter().all(|&x| x == 0.0));
+        assert!(envelope.is_finished());
+    }
+
+    #[test]
+    fn punch_overshoots_the_peak_and_then_settles() {
+        let mut envelope = ADSR {
+            punch: 0.5,
+            ..test_envelope()
+        };
+        let output = envelope.render(SAMPLE_RATE, SAMPLE_RATE as usize, SAMPLE_RATE as usize);
+
+        let peak_index = (0.01 * SAMPLE_RATE) as usize - 1;
+        assert_eq!(output[peak_index], 1.5);
+        // The decay brings it back down to the sustain level, where it stays
+        let decay_end = (0.06 * SAMPLE_RATE) as usize;
+        assert!(output[peak_index + 1..decay_end].iter().all(|&x| x < 1.5));
+        assert!(output[decay_end + 1..].iter().all(|&x| x == 0.5));
+    }
+
+    #[test]
+    fn second_decay_passes_through_the_break_level() {
+        let mut envelope = ADSR {
+            decay_2: 0.1,
+            break_level: 0.7,
+            ..test_envelope()
+        };
+        let output = envelope.render(SAMPLE_RATE, SAMPLE_RATE as usize, SAMPLE_RATE as usize);
+
+        // The first decay ends exactly on the break level, 60 ms in
+        let break_index = output.iter().position(|&x| x == 0.7).unwrap();
+        assert!((break_index as f32 / SAMPLE_RATE - 0.06).abs() < 2.0 / SAMPLE_RATE);
+        let peak_index = (0.01 * SAMPLE_RATE) as usize - 1;
+        assert!(output[peak_index + 1..break_index].iter().all(|&x| x > 0.7));
+
+        // The second decay then falls steadily from there to the sustain level over 100 ms
+        let sustain_index = output.iter().position(|&x| x == 0.5).unwrap();
+        let second_decay_time = (sustain_index - break_index) as f32 / SAMPLE_RATE;
+        assert!((second_decay_time - 0.1).abs() < 2.0 / SAMPLE_RATE);
+        for pair in output[break_index..=sustain_index].windows(2) {
+            assert!(pair[1] < pair[0]);
+        }
+        assert!(output[sustain_index..].iter().all(|&x| x == 0.5));
+    }
+
+    #[test]
+    fn attack_rises_steadily_into_the_decay() {
+        let mut envelope = test_envelope();
+        let output = envelope.render(SAMPLE_RATE, SAMPLE_RATE as usize, SAMPLE_RATE as usize);
+
+        let attack_step = 1.0 / (0.01 * SAMPLE_RATE);
+        let decay_step = 0.5 / (0.05 * SAMPLE_RATE);
+        let peak_index = (0.01 * SAMPLE_RATE) as usize - 1;
+        assert!((output[0] - attack_step).abs() < 1e-4);
+        for pair in output[..=peak_index].windows(2) {
+            assert!(pair[1] > pair[0]);
+            assert!(pair[1] - pair[0] < attack_step * 1.01);
+        }
+
+        // The decay picks up one step after the peak, instead of repeating it or jumping ahead
+        let first_decay_step = output[peak_index] - output[peak_index + 1];
+        assert!((first_decay_step - decay_step).abs() < decay_step * 0.01);
+    }
+
+    #[test]
+    fn retriggering_during_the_decay_continues_from_the_current_level() {
+        for decay_2 in [0.0, 1.0] {
+            let mut envelope = ADSR {
+                decay: 1.0,
+                decay_2,
+                sustain: 0.0,
+                ..test_envelope()
+            };
+            // Halfway through the last decay stage
+            let samples = ((0.5 + decay_2) * SAMPLE_RATE) as usize;
+            envelope.render(SAMPLE_RATE, samples, samples);
+            let expected_stage = if decay_2 > 0.0 {
+                EnvelopeStage::SecondDecay
+            } else {
+                EnvelopeStage::Decay
+            };
+            let mut previous = envelope.current_level;
+            assert!(envelope.stage == expected_stage && previous > 0.0);
+
+            envelope.retrigger(RetriggerStage::Attack);
+            for _ in 0..1000 {
+                let level = envelope.next(SAMPLE_RATE);
+                assert!(level <= previous && previous - level < 1e-3);
+                previous = level;
+            }
+        }
+    }
+
+    #[test]
+    fn short_releases_fade_out_linearly_over_the_minimum_length() {
+        for release in [0.0, 0.0005] {
+            let mut envelope = ADSR {
+                release,
+                ..test_envelope()
+            };
+            let gate_samples = SAMPLE_RATE as usize / 2;
+            let output = envelope.render(SAMPLE_RATE, gate_samples, gate_samples + 100);
+
+            let fade = &output[gate_samples..];
+            let fade_samples = MIN_RELEASE_SAMPLES as usize;
+            for (i, &level) in fade[..fade_samples].iter().enumerate() {
+                let expected = 0.5 * (1.0 - (i + 1) as f32 / MIN_RELEASE_SAMPLES);
+                assert!((level - expected).abs() < 1e-4);
+            }
+            assert!(fade[fade_samples - 2] > 0.0);
+            assert!(fade[fade_samples - 1..].iter().all(|&x| x == 0.0));
+        }
+    }
+
+    #[test]
+    fn next_after_reset_is_silent() {
+        let mut envelope = test_envelope();
+        envelope.render(SAMPLE_RATE, 1000, 1010);
+        assert!(envelope.current_level > 0.0);
+
+        envelope.reset();
+        assert_eq!(envelope.next(SAMPLE_RATE), 0.0);
+        assert!(envelope.is_finished());
+    }
+}
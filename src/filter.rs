@@ -1,12 +1,177 @@
+use crate::util::{self, Rng};
+use nih_plug::nih_debug_assert;
+use nih_plug::prelude::Enum;
 use std::f32::consts;
 
+/// The highest resonance the filter is meant to be run at.
+pub const MAX_RESONANCE: f32 = 100.0;
+/// Resonance above which the damping starts being lowered towards self-oscillation. Everything
+/// below this is a plain linear filter that rings out and never has noise injected into it.
+const SELF_OSCILLATION_RESONANCE: f32 = 95.0;
+/// From `SELF_OSCILLATION_RESONANCE` up to `MAX_RESONANCE` the damping is lowered by up to this
+/// much on top of the usual `1 / resonance`. It crosses zero at a resonance of about 96, past which
+/// the filter's own resonance outgrows its losses and it oscillates without any input. Only there
+/// is the seed noise injected.
+const SELF_OSCILLATION_DAMPING: f32 = 0.06;
+/// How much the damping rises with the square of the band pass state while self-oscillating. This
+/// is what keeps a self-oscillating filter from growing forever, and settles it at about the same
+/// level at every cutoff, roughly -8 dBFS at `MAX_RESONANCE`.
+const SELF_OSCILLATION_LIMIT: f32 = 0.4;
+/// The default seed level, roughly -80 dBFS.
+const DEFAULT_SEED: f32 = 0.0001;
+/// The lowest normalized frequency and resonance `set_f_q` accepts. As long as both `g` and `r`
+/// stay positive the denominator of `h` can't drop below 1, so the filter is always stable.
+const MIN_FREQUENCY: f32 = 0.000_01;
+const MIN_RESONANCE: f32 = 0.01;
+/// The relative change in frequency or resonance `set_f_q` ignores. The cutoff and resonance are
+/// set every sample but mostly sit still, and recomputing `tan` for nothing isn't free.
+const COEFFICIENT_TOLERANCE: f32 = 0.000_1;
+/// The level below which the filter's state counts as silent, roughly -120 dBFS.
+const SILENCE_THRESHOLD: f32 = 0.000_001;
+/// The resonance of the first stage of the 24 dB/oct cascade, the lower of the two stage
+/// resonances of a fourth order Butterworth filter. The first stage stays this gentle so the
+/// resonance control only sets the peak of the second stage.
+const CASCADE_FIRST_STAGE_RESONANCE: f32 = 0.541;
+/// The output is scaled by `1 / resonance` raised to this power at the full resonance
+/// compensation. The resonant peak makes the filter louder as the resonance rises, and this
+/// roughly evens that out for a bright oscillator a few octaves below the cutoff.
+const RESONANCE_COMPENSATION_EXPONENT: f32 = 0.25;
+/// How much harder the band pass state is driven into the saturation at the full filter drive.
+const MAX_FEEDBACK_DRIVE: f32 = 15.0;
+
+#[derive(Enum, Clone, Copy, PartialEq)]
 pub enum FilterMode {
+    #[name = "Low Pass"]
     LowPass,
+    #[name = "Band Pass"]
     BandPass,
+    #[name = "High Pass"]
     HighPass,
+    /// The sum of the low and high pass outputs. The two are out of phase at the cutoff and cancel
+    /// out there, leaving everything but the band around it.
+    Notch,
+    /// The low pass output minus the high pass output, which boosts the band around the cutoff by
+    /// the resonance and leaves the rest of the spectrum flat.
+    Peak,
+}
+
+/// Where the filter sits relative to the amp envelope.
+#[derive(Enum, Clone, Copy, PartialEq)]
+pub enum FilterRouting {
+    /// The filter always sees the oscillator at full level, so its resonance and drive behave the
+    /// same no matter where the envelope is.
+    #[name = "Pre Envelope"]
+    PreEnvelope,
+    /// The filter sees the enveloped signal, so a slow attack also eases into the resonance.
+    #[name = "Post Envelope"]
+    PostEnvelope,
+}
+
+/// How steeply the filter rolls off past the cutoff.
+#[derive(Enum, Clone, Copy, PartialEq)]
+pub enum FilterSlope {
+    #[name = "12 dB/oct"]
+    Db12,
+    #[name = "24 dB/oct"]
+    Db24,
+}
+
+/// Two `Svf`s in series. At 12 dB/oct only the first stage runs and the cascade behaves exactly
+/// like a single `Svf`. At 24 dB/oct both stages run, which costs twice as much CPU.
+pub struct CascadedSvf {
+    pub mode: FilterMode,
+    pub drive: f32,
+
+    slope: FilterSlope,
+
+    stages: [Svf; 2],
+}
+
+impl Default for CascadedSvf {
+    fn default() -> Self {
+        Self {
+            mode: FilterMode::LowPass,
+            drive: 0.0,
+
+            slope: FilterSlope::Db12,
+
+            stages: [Svf::default(), Svf::default()],
+        }
+    }
+}
+
+impl CascadedSvf {
+    /// Set frequency and resonance from true units. At 24 dB/oct the resonance is applied to the
+    /// second stage, which is the one that rings and self-oscillates.
+    pub fn set_f_q(&mut self, f: f32, resonance: f32) {
+        match self.slope {
+            FilterSlope::Db12 => self.stages[0].set_f_q(f, resonance),
+            FilterSlope::Db24 => {
+                self.stages[0].set_f_q(f, CASCADE_FIRST_STAGE_RESONANCE);
+                self.stages[1].set_f_q(f, resonance);
+            }
+        }
+    }
+
+    /// Switch between 12 and 24 dB/oct. The second stage is cleared whenever the slope changes, so
+    /// it doesn't ring with stale state when it's switched back on.
+    pub fn set_slope(&mut self, slope: FilterSlope) {
+        if slope != self.slope {
+            self.slope = slope;
+            self.stages[1].reset();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for stage in &mut self.stages {
+            stage.reset();
+        }
+    }
+
+    /// Whether every stage that's in use has rung out.
+    pub fn is_silent(&self) -> bool {
+        match self.slope {
+            FilterSlope::Db12 => self.stages[0].is_silent(),
+            FilterSlope::Db24 => self.stages.iter().all(Svf::is_silent),
+        }
+    }
+
+    pub fn process(&mut self, i: f32) -> f32 {
+        for stage in &mut self.stages {
+            stage.mode = self.mode;
+            stage.drive = self.drive;
+        }
+
+        let o = self.stages[0].process(i);
+        match self.slope {
+            FilterSlope::Db12 => o,
+            FilterSlope::Db24 => self.stages[1].process(o),
+        }
+    }
+
+    pub fn set_resonance_compensation(&mut self, amount: f32) {
+        for stage in &mut self.stages {
+            stage.set_resonance_compensation(amount);
+        }
+    }
+
+    /// Restart the self-oscillation seed noise of both stages from `seed`. Each stage gets its own
+    /// stream, so the two don't inject the exact same noise.
+    pub fn reseed_noise(&mut self, seed: u32) {
+        for (i, stage) in self.stages.iter_mut().enumerate() {
+            stage.reseed_noise(util::stream_seed(seed, i as u32));
+        }
+    }
 }
+
 pub struct Svf {
-    mode: FilterMode,
+    /// Which of the filter's outputs `process()` returns. All of them come from the same state, so
+    /// this can be changed at any time without resetting the filter.
+    pub mode: FilterMode,
+
+    /// The frequency and resonance the current coefficients were computed for.
+    f: f32,
+    resonance: f32,
 
     g: f32,
     r: f32,
@@ -14,6 +179,20 @@ pub struct Svf {
 
     state_1: f32,
     state_2: f32,
+
+    /// How much the output is turned down as the resonance rises, in `[0, 1]`, and the gain that
+    /// works out to for the current resonance. Resonances below 1 are left alone.
+    resonance_compensation: f32,
+    compensation_gain: f32,
+
+    /// The level of the noise injected into the input at very high resonance. A linear filter has
+    /// nothing to ring with when its input is silent, so this gives it something to self-oscillate
+    /// from when it's used as a sine source.
+    pub seed: f32,
+    /// How hard the resonance is driven into saturation, in `[0, 1]`. At 0 the filter is linear.
+    pub drive: f32,
+    self_oscillating: bool,
+    noise: Rng,
 }
 
 impl Default for Svf {
@@ -21,11 +200,22 @@ impl Default for Svf {
         let mut this = Self {
             mode: FilterMode::LowPass,
 
+            f: 0.0,
+            resonance: 0.0,
+
             g: 0.0,
             r: 0.0,
             h: 0.0,
             state_1: 0.0,
             state_2: 0.0,
+
+            resonance_compensation: 0.0,
+            compensation_gain: 1.0,
+
+            seed: DEFAULT_SEED,
+            drive: 0.0,
+            self_oscillating: false,
+            noise: Rng::new(1),
         };
 
         this.set_f_q(22_000.0, 1.0);
@@ -37,28 +227,449 @@ impl Default for Svf {
 impl Svf {
     /// Set frequency and resonance from true units.
     pub fn set_f_q(&mut self, f: f32, resonance: f32) {
-        self.g = tan(f);
-        self.r = 1.0 / resonance;
+        if (f - self.f).abs() <= self.f * COEFFICIENT_TOLERANCE
+            && (resonance - self.resonance).abs() <= self.resonance * COEFFICIENT_TOLERANCE
+        {
+            return;
+        }
+        self.f = f;
+        self.resonance = resonance;
+
+        // A negative `g` or `r` (from modulation pushing the cutoff below zero, for instance) can
+        // make the denominator below cross zero and blow the filter up
+        self.g = tan(f.max(MIN_FREQUENCY));
+        self.r = damping(resonance);
         self.h = 1.0 / (1.0 + self.r * self.g + self.g * self.g);
+        nih_debug_assert!(
+            self.h.is_finite() && self.h > 0.0,
+            "Unstable filter coefficients for f = {f}, resonance = {resonance}"
+        );
+        self.self_oscillating = self_oscillates(resonance);
+        self.update_compensation_gain();
+    }
+
+    /// Keep the filter's level roughly constant across the resonance range. At 0 the output is
+    /// left untouched.
+    pub fn set_resonance_compensation(&mut self, amount: f32) {
+        if amount != self.resonance_compensation {
+            self.resonance_compensation = amount;
+            self.update_compensation_gain();
+        }
+    }
+
+    fn update_compensation_gain(&mut self) {
+        // The damping goes negative while self-oscillating, so this works from the resonance
+        self.compensation_gain = (1.0 / self.resonance.max(MIN_RESONANCE))
+            .min(1.0)
+            .powf(self.resonance_compensation * RESONANCE_COMPENSATION_EXPONENT);
+    }
+
+    /// Clear the filter's state, so the next input starts from silence.
+    pub fn reset(&mut self) {
+        self.state_1 = 0.0;
+        self.state_2 = 0.0;
+    }
+
+    /// Whether the filter has rung out and, with a silent input, would only output silence. A self
+    /// oscillating filter never counts as silent.
+    pub fn is_silent(&self) -> bool {
+        !self.self_oscillating
+            && self.state_1.abs() < SILENCE_THRESHOLD
+            && self.state_2.abs() < SILENCE_THRESHOLD
     }
 
     pub fn process(&mut self, i: f32) -> f32 {
-        let hp = (i - self.r * self.state_1 - self.g * self.state_1 - self.state_2) * self.h;
+        let (i, r, h) = if self.self_oscillating {
+            let r = self.r + SELF_OSCILLATION_LIMIT * self.state_1 * self.state_1;
+            let h = 1.0 / (1.0 + r * self.g + self.g * self.g);
+            (i + self.seed * self.noise.next_bipolar(), r, h)
+        } else {
+            (i, self.r, self.h)
+        };
+
+        let hp = (i - r * self.state_1 - self.g * self.state_1 - self.state_2) * h;
         let bp = self.g * hp + self.state_1;
         let lp = self.g * bp + self.state_2;
 
         self.state_1 = self.g * hp + bp;
+        if self.drive > 0.0 {
+            // The band pass state carries the resonance, so saturating it squashes and roughens
+            // the resonant peak instead of letting it ring ever louder. The low pass state isn't
+            // touched, so the passband level stays the same.
+            let drive = 1.0 + self.drive * MAX_FEEDBACK_DRIVE;
+            self.state_1 = util::soft_limit(self.state_1 * drive) / drive;
+        }
         self.state_2 = self.g * bp + lp;
 
-        match self.mode {
+        let output = match self.mode {
             FilterMode::LowPass => lp,
             FilterMode::BandPass => bp,
             FilterMode::HighPass => hp,
-        }
+            FilterMode::Notch => lp + hp,
+            FilterMode::Peak => lp - hp,
+        };
+
+        output * self.compensation_gain
+    }
+
+    /// Restart the self-oscillation seed noise from `seed`.
+    pub fn reseed_noise(&mut self, seed: u32) {
+        self.noise = Rng::new(seed);
     }
 }
 
+/// The filter's damping `r` at `resonance`. This is `1 / resonance`, lowered past zero at the very
+/// top of the resonance range so the filter can self-oscillate.
+fn damping(resonance: f32) -> f32 {
+    1.0 / resonance.max(MIN_RESONANCE)
+        - SELF_OSCILLATION_DAMPING
+            * ((resonance - SELF_OSCILLATION_RESONANCE)
+                / (MAX_RESONANCE - SELF_OSCILLATION_RESONANCE))
+                .clamp(0.0, 1.0)
+}
+
+/// Whether a filter running at `resonance` self-oscillates, and so never rings out even with a
+/// silent input.
+pub fn self_oscillates(resonance: f32) -> bool {
+    damping(resonance) <= 0.0
+}
+
+/// `tan(x * PI)` for the normalized cutoff frequency `x`, which prewarps the cutoff so the filter's
+/// response lands exactly on it all the way up to Nyquist. The frequency is clamped just below
+/// Nyquist, where the tangent blows up.
 fn tan(x: f32) -> f32 {
     let f = if x < 0.497 { x } else { 0.497 };
-    (f * consts::PI).tan()
+
+    // Above a quarter of the sample rate the tangent is computed from its complement, so the
+    // approximation only ever sees angles up to PI / 4
+    if f <= 0.25 {
+        tan_quarter(f * consts::PI)
+    } else {
+        1.0 / tan_quarter((0.5 - f) * consts::PI)
+    }
+}
+
+/// A Padé approximation of `tan(x)` for `x` in `[0, PI / 4]`. It's within single precision
+/// rounding of the real thing over that range, without a libm call.
+fn tan_quarter(x: f32) -> f32 {
+    let x2 = x * x;
+    x * (945.0 - 105.0 * x2 + x2 * x2) / (945.0 - 420.0 * x2 + 15.0 * x2 * x2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    /// The low pass gain at `frequency` of a Butterworth `Svf` with its cutoff at `cutoff`, both in
+    /// Hertz, measured from a sine once the filter has settled.
+    fn butterworth_gain_db(cutoff: f32, frequency: f32) -> f32 {
+        let mut filter = Svf::default();
+        filter.set_f_q(cutoff / SAMPLE_RATE, consts::FRAC_1_SQRT_2);
+        let output: Vec<f32> = (0..SAMPLE_RATE as usize / 10)
+            .map(|n| filter.process((consts::TAU * frequency * n as f32 / SAMPLE_RATE).sin()))
+            .collect();
+
+        20.0 * (rms(&output[output.len() / 2..]) / consts::FRAC_1_SQRT_2).log10()
+    }
+
+    #[test]
+    fn max_resonance_self_oscillates_with_a_silent_input() {
+        for frequency in [110.0, 440.0, 1760.0] {
+            let mut filter = Svf::default();
+            filter.set_f_q(frequency / SAMPLE_RATE, MAX_RESONANCE);
+            let output: Vec<f32> = (0..SAMPLE_RATE as usize * 2)
+                .map(|_| filter.process(0.0))
+                .collect();
+
+            // The last two quarter seconds are just as loud, so the oscillation has settled
+            // instead of dying out or still growing
+            let quarter = SAMPLE_RATE as usize / 4;
+            let late = rms(&output[output.len() - quarter..]);
+            let earlier = rms(&output[output.len() - 2 * quarter..output.len() - quarter]);
+            assert!(late > 0.1, "{frequency} Hz only reached an RMS of {late}");
+            assert!(
+                (late / earlier - 1.0).abs() < 0.05,
+                "{frequency} Hz went from an RMS of {earlier} to {late}"
+            );
+            assert!(output.iter().all(|x| x.abs() < 1.0));
+        }
+    }
+
+    #[test]
+    fn normal_resonance_stays_silent_with_a_silent_input() {
+        let mut filter = Svf::default();
+        filter.set_f_q(440.0 / SAMPLE_RATE, 10.0);
+        assert!((0..SAMPLE_RATE as usize).all(|_| filter.process(0.0) == 0.0));
+        assert!(filter.is_silent());
+    }
+
+    #[test]
+    fn only_the_top_of_the_resonance_range_self_oscillates() {
+        assert!(!self_oscillates(SELF_OSCILLATION_RESONANCE));
+        assert!(self_oscillates(0.97 * MAX_RESONANCE));
+
+        // Right below the self-oscillating range the filter still rings out on its own, without
+        // any seed noise
+        let mut filter = Svf::default();
+        filter.set_f_q(440.0 / SAMPLE_RATE, 0.95 * MAX_RESONANCE);
+        filter.process(1.0);
+        let output: Vec<f32> = (0..SAMPLE_RATE as usize * 2)
+            .map(|_| filter.process(0.0))
+            .collect();
+        assert!(output[output.len() - 100..].iter().all(|x| x.abs() < 1e-6));
+    }
+
+    #[test]
+    fn full_scale_input_stays_bounded_across_the_cutoff_and_resonance_ranges() {
+        let cutoffs = [
+            -0.1,
+            0.0,
+            MIN_FREQUENCY,
+            0.001,
+            0.01,
+            0.1,
+            0.25,
+            0.45,
+            0.497,
+            0.5,
+            0.7,
+        ];
+        let resonances = [
+            -1.0,
+            0.0,
+            MIN_RESONANCE,
+            0.1,
+            consts::FRAC_1_SQRT_2,
+            1.0,
+            5.0,
+            20.0,
+            60.0,
+            SELF_OSCILLATION_RESONANCE,
+            96.0,
+        ];
+        for mode in [
+            FilterMode::LowPass,
+            FilterMode::BandPass,
+            FilterMode::HighPass,
+            FilterMode::Notch,
+            FilterMode::Peak,
+        ] {
+            for &f in &cutoffs {
+                for resonance in resonances.into_iter().chain([MAX_RESONANCE]) {
+                    let mut filter = Svf {
+                        mode,
+                        ..Svf::default()
+                    };
+                    filter.set_f_q(f, resonance);
+
+                    // A full-scale sine right at the cutoff, where the resonant peak is
+                    let phase_delta = f.clamp(MIN_FREQUENCY, 0.497);
+                    let peak = (0..SAMPLE_RATE as usize / 2)
+                        .map(|n| {
+                            let i = (n as f32 * phase_delta * consts::TAU).sin();
+                            filter.process(i).abs()
+                        })
+                        .fold(0.0, f32::max);
+                    // The peak is the resonance at most, until the self-oscillation limiting
+                    // takes over. The peak mode adds the low and high pass peaks together, which
+                    // doubles it.
+                    let mode_gain = if mode == FilterMode::Peak { 2.0 } else { 1.0 };
+                    let max_peak =
+                        resonance.clamp(1.0, SELF_OSCILLATION_RESONANCE) * mode_gain * 1.01;
+                    assert!(
+                        peak.is_finite() && peak <= max_peak,
+                        "f = {f}, resonance = {resonance} peaked at {peak}"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture` to print the numbers.
+    #[test]
+    #[ignore = "benchmark"]
+    fn benchmark_steady_coefficients() {
+        const SAMPLES: usize = 10_000_000;
+
+        let time_per_sample = |f: &dyn Fn(usize) -> f32| {
+            let mut filter = Svf::default();
+            let start = std::time::Instant::now();
+            for n in 0..SAMPLES {
+                filter.set_f_q(f(n), 2.0);
+                std::hint::black_box(filter.process(std::hint::black_box(0.5)));
+            }
+
+            start.elapsed().as_secs_f32() / SAMPLES as f32 * 1e9
+        };
+        // Every change is bigger than the tolerance, so the coefficients are recomputed each time
+        let moving = time_per_sample(&|n| 0.01 + (n % 2) as f32 * 0.001);
+        let steady = time_per_sample(&|_| 0.01);
+
+        println!("moving cutoff: {moving:.2} ns/sample, steady cutoff: {steady:.2} ns/sample");
+        assert!(steady < moving);
+    }
+
+    #[test]
+    fn switching_the_slope_back_on_starts_from_silence() {
+        // With this resonance the first stage is set up the same way at either slope
+        let process = |filter: &mut CascadedSvf, slope, samples| {
+            filter.set_slope(slope);
+            (0..samples)
+                .map(|n| {
+                    filter.set_f_q(0.01, CASCADE_FIRST_STAGE_RESONANCE);
+                    filter.process(if n % 100 < 50 { 1.0 } else { -1.0 })
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut switched = CascadedSvf::default();
+        process(&mut switched, FilterSlope::Db24, 1000);
+        process(&mut switched, FilterSlope::Db12, 1000);
+        let mut fresh = CascadedSvf::default();
+        process(&mut fresh, FilterSlope::Db12, 2000);
+
+        assert_eq!(
+            process(&mut switched, FilterSlope::Db24, 1000),
+            process(&mut fresh, FilterSlope::Db24, 1000)
+        );
+    }
+
+    #[test]
+    fn cutoff_is_at_minus_3_db_up_to_18_khz() {
+        for cutoff in [1_000.0, 2_000.0, 5_000.0, 10_000.0, 14_000.0, 18_000.0] {
+            let gain_db = butterworth_gain_db(cutoff, cutoff);
+            assert!(
+                (gain_db + 3.01).abs() < 0.1,
+                "The gain at a {cutoff} Hz cutoff is {gain_db} dB"
+            );
+        }
+    }
+
+    #[test]
+    fn measured_corner_frequency_matches_the_cutoff() {
+        for cutoff in [200.0, 1_000.0, 5_000.0, 10_000.0, 18_000.0] {
+            // The gain only falls as the frequency goes up, so bisect for where it crosses -3 dB
+            let (mut low, mut high) = (cutoff / 2.0, (cutoff * 1.25_f32).min(23_000.0));
+            for _ in 0..20 {
+                let middle = (low + high) / 2.0;
+                if butterworth_gain_db(cutoff, middle) > -3.01 {
+                    low = middle;
+                } else {
+                    high = middle;
+                }
+            }
+
+            let corner = (low + high) / 2.0;
+            assert!(
+                (corner / cutoff - 1.0).abs() < 0.01,
+                "A {cutoff} Hz cutoff measured its -3 dB point at {corner} Hz"
+            );
+        }
+    }
+
+    #[test]
+    fn notch_dips_white_noise_at_the_cutoff() {
+        // The energy of one second of `samples` at a whole number of Hertz
+        let energy_at = |samples: &[f32], frequency: f32| {
+            let (re, im) = samples
+                .iter()
+                .enumerate()
+                .fold((0.0, 0.0), |(re, im), (n, x)| {
+                    let phase = consts::TAU * frequency * n as f32 / SAMPLE_RATE;
+                    (re + x * phase.cos(), im + x * phase.sin())
+                });
+
+            re * re + im * im
+        };
+
+        let mut rng = Rng::new(1);
+        let noise: Vec<f32> = (0..SAMPLE_RATE as usize)
+            .map(|_| rng.next_bipolar())
+            .collect();
+        let mut notch = Svf {
+            mode: FilterMode::Notch,
+            ..Svf::default()
+        };
+        notch.set_f_q(2_400.0 / SAMPLE_RATE, 1.0);
+        let notched: Vec<f32> = noise.iter().map(|&x| notch.process(x)).collect();
+
+        let dip_db = |frequency: f32| {
+            util::gain_to_db(energy_at(&notched, frequency) / energy_at(&noise, frequency)) / 2.0
+        };
+        assert!(
+            dip_db(2_400.0) < -30.0,
+            "The notch only dipped {} dB",
+            dip_db(2_400.0)
+        );
+        // Two octaves away the noise passes through almost untouched
+        assert!(dip_db(600.0).abs() < 0.5);
+        assert!(dip_db(9_600.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn zero_drive_is_the_clean_linear_filter() {
+        let mut filter = Svf {
+            drive: 0.0,
+            ..Svf::default()
+        };
+        filter.set_f_q(0.02, 10.0);
+        let (g, r, h) = (filter.g, filter.r, filter.h);
+        let (mut state_1, mut state_2) = (0.0, 0.0);
+
+        for n in 0..4_800 {
+            let i = if n % 200 < 100 { 1.0 } else { -1.0 };
+            let hp = (i - r * state_1 - g * state_1 - state_2) * h;
+            let bp = g * hp + state_1;
+            let lp = g * bp + state_2;
+            state_1 = g * hp + bp;
+            state_2 = g * bp + lp;
+
+            assert_eq!(filter.process(i), lp);
+        }
+    }
+
+    #[test]
+    fn full_drive_at_max_resonance_stays_bounded() {
+        for f in [0.001, 0.01, 0.1, 0.45] {
+            let mut filter = Svf {
+                drive: 1.0,
+                ..Svf::default()
+            };
+            filter.set_f_q(f, MAX_RESONANCE);
+
+            // A full-scale sine right at the cutoff drives the resonance as hard as it goes
+            let peak = (0..SAMPLE_RATE as usize)
+                .map(|n| filter.process((n as f32 * f * consts::TAU).sin()).abs())
+                .fold(0.0, f32::max);
+            assert!(peak.is_finite() && peak < 2.0, "f = {f} peaked at {peak}");
+        }
+    }
+
+    #[test]
+    fn resonance_compensation_evens_out_the_level_across_resonances() {
+        // The level of a sine right at the cutoff, which the resonant peak boosts the most
+        let level_db = |resonance: f32, compensation: f32| {
+            let mut filter = Svf::default();
+            filter.set_f_q(1_000.0 / SAMPLE_RATE, resonance);
+            filter.set_resonance_compensation(compensation);
+            let output: Vec<f32> = (0..SAMPLE_RATE as usize / 2)
+                .map(|n| filter.process((consts::TAU * 1_000.0 * n as f32 / SAMPLE_RATE).sin()))
+                .collect();
+
+            util::gain_to_db(rms(&output[output.len() / 2..]))
+        };
+
+        let uncompensated = level_db(10.0, 0.0) - level_db(1.0, 0.0);
+        let compensated = level_db(10.0, 1.0) - level_db(1.0, 1.0);
+        assert!((uncompensated - 20.0).abs() < 0.5);
+        assert!(compensated < uncompensated - 4.0);
+        // Resonances up to 1 don't have a peak to compensate for
+        assert_eq!(level_db(0.5, 0.0), level_db(0.5, 1.0));
+    }
 }
@@ -0,0 +1,136 @@
+/// The length of the loop the freeze replays.
+const LOOP_MS: f32 = 200.0;
+/// How long it takes to fade between the live signal and the loop when freezing or unfreezing, and
+/// the length of the crossfade hiding the loop point.
+const FADE_MS: f32 = 10.0;
+
+/// Freezes a signal by replaying the last `LOOP_MS` milliseconds of it in a loop. While not frozen
+/// the input is continuously recorded, so the loop is always whatever played right before freezing.
+#[derive(Default)]
+pub struct Freezer {
+    /// The recording, with room for the loop and the crossfade leading into it. Allocated in
+    /// `resize()` since its length depends on the sample rate.
+    buffer: Vec<f32>,
+    write_pos: usize,
+    loop_len: usize,
+    fade_len: usize,
+
+    /// Where the oldest sample of the recording was when the loop was captured.
+    loop_start: usize,
+    /// The position within the loop, in `[0, loop_len)`.
+    play_pos: usize,
+    /// How much of the loop is heard instead of the live signal.
+    mix: f32,
+}
+
+impl Freezer {
+    /// Allocate the recording buffer for this sample rate. Should only be called from
+    /// `initialize()`. If the sample rate didn't change the recording is kept as is, so a frozen
+    /// loop keeps playing.
+    pub fn resize(&mut self, sample_rate: f32) {
+        let loop_len = ((LOOP_MS / 1000.0 * sample_rate).round() as usize).max(1);
+        if loop_len == self.loop_len && !self.buffer.is_empty() {
+            return;
+        }
+
+        self.loop_len = loop_len;
+        self.fade_len = ((FADE_MS / 1000.0 * sample_rate).round() as usize).clamp(1, self.loop_len);
+
+        self.buffer = vec![0.0; self.loop_len + self.fade_len];
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.buffer.fill(0.0);
+        self.write_pos = 0;
+        self.loop_start = 0;
+        self.play_pos = 0;
+        self.mix = 0.0;
+    }
+
+    /// Record the input, or replay the captured loop while `freeze` is set. Switching between the
+    /// two fades over `FADE_MS` so neither causes a click.
+    pub fn process(&mut self, input: f32, freeze: bool) -> f32 {
+        if self.buffer.is_empty() {
+            return input;
+        }
+
+        let fade_step = 1.0 / self.fade_len as f32;
+        if freeze {
+            // Freezing again while the previous loop is still fading out keeps that loop
+            if self.mix == 0.0 {
+                self.loop_start = self.write_pos;
+                self.play_pos = 0;
+            }
+            self.mix = (self.mix + fade_step).min(1.0);
+        } else {
+            self.mix = (self.mix - fade_step).max(0.0);
+        }
+
+        if self.mix == 0.0 {
+            self.buffer[self.write_pos] = input;
+            self.write_pos = (self.write_pos + 1) % self.buffer.len();
+
+            return input;
+        }
+
+        let looped = self.next_loop_sample();
+        input + (looped - input) * self.mix
+    }
+
+    /// The next sample of the loop. The loop skips the first `fade_len` recorded samples, and
+    /// crossfades its end into them so it wraps back around to its start seamlessly.
+    fn next_loop_sample(&mut self) -> f32 {
+        let recorded = |i: usize| self.buffer[(self.loop_start + i) % self.buffer.len()];
+
+        let fade_start = self.loop_len - self.fade_len;
+        let mut value = recorded(self.fade_len + self.play_pos);
+        if self.play_pos >= fade_start {
+            let fade_pos = self.play_pos - fade_start;
+            let t = (fade_pos + 1) as f32 / self.fade_len as f32;
+            value += (recorded(fade_pos) - value) * t;
+        }
+
+        self.play_pos = (self.play_pos + 1) % self.loop_len;
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    #[test]
+    fn frozen_output_repeats_the_captured_audio() {
+        let mut freezer = Freezer::default();
+        freezer.resize(SAMPLE_RATE);
+        let input = |n: usize| (n as f32 * 0.01).sin();
+
+        let freeze_at = 20_000;
+        for n in 0..freeze_at {
+            assert_eq!(freezer.process(input(n), false), input(n));
+        }
+        // The live input is silent from here on, so only the loop is heard
+        let frozen: Vec<f32> = (0..3 * freezer.loop_len)
+            .map(|_| freezer.process(0.0, true))
+            .collect();
+
+        // Once faded in, the loop plays back what came in right before freezing
+        let (loop_len, fade_len) = (freezer.loop_len, freezer.fade_len);
+        for (p, &x) in frozen
+            .iter()
+            .enumerate()
+            .take(loop_len - fade_len)
+            .skip(fade_len)
+        {
+            assert_eq!(x, input(freeze_at - loop_len + p));
+        }
+        // And then repeats it
+        for n in fade_len..2 * loop_len {
+            assert_eq!(frozen[n + loop_len], frozen[n]);
+        }
+    }
+}
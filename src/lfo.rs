@@ -0,0 +1,93 @@
+use nih_plug::prelude::Enum;
+use std::f32::consts;
+
+/// The number of entries in one period of the sine lookup table.
+const SINE_TABLE_SIZE: usize = 256;
+
+#[derive(Enum, Clone, Copy, PartialEq)]
+pub enum LfoShape {
+    Sine,
+    Square,
+}
+
+/// Note lengths an LFO's period can be synced to.
+#[derive(Enum, Clone, Copy, PartialEq)]
+pub enum NoteDivision {
+    #[name = "1/1"]
+    Whole,
+    #[name = "1/2"]
+    Half,
+    #[name = "1/4"]
+    Quarter,
+    #[name = "1/8"]
+    Eighth,
+    #[name = "1/16"]
+    Sixteenth,
+}
+
+impl NoteDivision {
+    /// The LFO frequency in Hertz for one period per note of this length at `tempo` BPM.
+    pub fn frequency(self, tempo: f32) -> f32 {
+        let beats = match self {
+            NoteDivision::Whole => 4.0,
+            NoteDivision::Half => 2.0,
+            NoteDivision::Quarter => 1.0,
+            NoteDivision::Eighth => 0.5,
+            NoteDivision::Sixteenth => 0.25,
+        };
+
+        tempo / 60.0 / beats
+    }
+}
+
+/// A free running LFO, reading its sine from a lookup table. It keeps running between notes, so
+/// everything it modulates stays in phase with it.
+pub struct Lfo {
+    /// One period of a sine, with the first sample repeated at the end so interpolating never has
+    /// to wrap around.
+    table: [f32; SINE_TABLE_SIZE + 1],
+    /// The current phase, always kept between in `[0, 1)`.
+    phase: f32,
+}
+
+impl Default for Lfo {
+    fn default() -> Self {
+        let mut table = [0.0; SINE_TABLE_SIZE + 1];
+        for (i, sample) in table.iter_mut().enumerate() {
+            *sample = (i as f32 / SINE_TABLE_SIZE as f32 * consts::TAU).sin();
+        }
+
+        Self { table, phase: 0.0 }
+    }
+}
+
+impl Lfo {
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+
+    /// Advance the LFO by one sample, returning a value in `[-1, 1]`.
+    pub fn next(&mut self, shape: LfoShape, frequency: f32, sample_rate: f32) -> f32 {
+        let value = match shape {
+            LfoShape::Sine => {
+                let position = self.phase * SINE_TABLE_SIZE as f32;
+                let index = position as usize;
+                let fraction = position - index as f32;
+
+                self.table[index] + (self.table[index + 1] - self.table[index]) * fraction
+            }
+            LfoShape::Square => {
+                if self.phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+        };
+
+        self.phase += frequency / sample_rate;
+        self.phase -= self.phase.floor();
+
+        value
+    }
+}
@@ -1,15 +1,86 @@
+mod bitcrush;
 mod envelope;
 mod filter;
+mod freeze;
+mod lfo;
+mod noise;
+mod note_stack;
 mod oscillator;
+mod scope;
+mod util;
+mod widener;
 
-use envelope::EnvelopeStage;
+use envelope::{EnvelopeMode, GateMode, RetriggerStage};
+use filter::{FilterMode, FilterRouting, FilterSlope, MAX_RESONANCE};
+use lfo::{LfoShape, NoteDivision};
 use nih_plug::prelude::*;
+use noise::NoiseColor;
+use note_stack::NoteStack;
 use oscillator::analog::VariableSawOscillator;
-use std::{os::fd::FromRawFd, sync::Arc};
+use oscillator::digital::{DigitalOscillator, Shape, SubOctave};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+
+/// The ID the host uses to polyphonically modulate the filter cutoff.
+const CUTOFF_POLY_MOD_ID: u32 = 0;
+/// The seed every random source is restarted from on reset, unless another one was set with
+/// `Toby::set_rng_seed()`.
+const DEFAULT_RNG_SEED: u32 = 1;
+/// The random streams derived from the seed, one per randomized feature.
+const FILTER_NOISE_STREAM: u32 = 0;
+const SLOP_STREAM: u32 = 1;
+const NOISE_STREAM: u32 = 2;
+/// The most the analog slop detunes a note by in either direction, and the most it lengthens or
+/// shortens the amp envelope's times by, at the full slop amount. At the full slop amount a note
+/// played from silence also starts anywhere in the oscillator's cycle.
+const MAX_SLOP_DETUNE_CENTS: f32 = 15.0;
+const MAX_SLOP_TIME_JITTER: f32 = 0.2;
+/// Keyboard panning leaves this note centered, and pans notes this many semitones away from it
+/// all the way to one side at the full key pan amount.
+const KEY_PAN_CENTER_NOTE: f32 = 60.0;
+const KEY_PAN_SPREAD_SEMITONES: f32 = 36.0;
+
+/// The lowest and highest filter cutoffs. Modulation can't push the cutoff out of this range, and
+/// the filter makeup gain is relative to the highest cutoff.
+const MIN_CUTOFF: f32 = 1.0;
+const MAX_CUTOFF: f32 = 20_000.0;
+/// The furthest the envelope can sweep the filter cutoff in either direction, in octaves.
+const MAX_ENV_TO_CUTOFF_OCTAVES: f32 = 8.0;
+/// The note at which keyboard tracking leaves the cutoff unchanged, A4.
+const KEYTRACK_REFERENCE_FREQUENCY: f32 = 440.0;
+/// How much the filter makeup gain boosts the filter's input per octave the cutoff is lowered at
+/// the full makeup amount, and the most it will ever boost it by.
+const FILTER_MAKEUP_DB_PER_OCTAVE: f32 = 1.5;
+const MAX_FILTER_MAKEUP_DB: f32 = 12.0;
+/// How much the oscillator is boosted into the filter's soft saturation at full pre-filter drive.
+const MAX_PRE_FILTER_DRIVE_DB: f32 = 24.0;
+/// How long changes to the envelope times are smoothed over. Envelope stages ramp based on how far
+/// along they are, so jumping a stage's length while it's running would make its output jump too.
+const ENVELOPE_TIME_SMOOTHING_MS: f32 = 50.0;
+/// How many octaves the morph control raises the filter resonance by at full morph and with the
+/// full morph to resonance amount.
+const MORPH_TO_RESONANCE_OCTAVES: f32 = 4.0;
+/// The voice is only released this many samples after the last note is let go of. A tight note off
+/// and note on pair for the same note is usually a host or controller retriggering it, and this
+/// way it restarts the attack without a sliver of release in between.
+const RETRIGGER_DEBOUNCE_SAMPLES: u32 = 16;
+/// How long a change in velocity between overlapping notes takes to ramp in.
+const VELOCITY_SMOOTHING_MS: f32 = 5.0;
+/// With hold-scaled releases, notes held at least this many seconds get the full release time and
+/// shorter notes get proportionally shorter releases, down to the minimum scale.
+const FULL_RELEASE_HOLD_TIME: f32 = 1.0;
+const MIN_HOLD_RELEASE_SCALE: f32 = 0.25;
 
 pub struct Toby {
     params: Arc<TobyParams>,
     sample_rate: f32,
+    /// Every randomized feature is seeded from its own stream derived from this whenever the plugin
+    /// is reset, so the same input always renders the same output.
+    rng_seed: u32,
+    /// Skips all smoothing, of the parameters and of everything else that would ramp, so changes
+    /// take effect on the very next sample. Enabled for offline renders, which then don't depend
+    /// on where a smoother was in its ramp. See `set_smoothing_bypassed()`.
+    bypass_smoothing: bool,
 
     /// The current phase of the sine wave, always kept between in `[0, 1]`.
     phase: f32,
@@ -18,34 +89,334 @@ pub struct Toby {
     midi_note_id: u8,
     /// The frequency if the active note, if triggered by MIDI.
     midi_note_freq: f32,
+    /// The MIDI channel of the active note.
+    midi_note_channel: u8,
+    /// The host's voice ID for the active note. Polyphonic modulation events are matched against
+    /// this, and it's reported back with a `VoiceTerminated` event once the voice finishes.
+    midi_note_voice_id: Option<i32>,
     /// A simple attack and release envelope to avoid clicks. Controlled through velocity and
     /// aftertouch.
     ///
     /// Smoothing is built into the parameters, but you can also use them manually if you need to
     /// smooth soemthing that isn't a parameter.
     midi_note_gain: Smoother<f32>,
+    /// The velocity the active note was played with. Unlike `midi_note_gain` this is held for the
+    /// whole note, including its release. Smoothed so playing over a sounding note with a
+    /// different velocity doesn't step its level.
+    midi_note_velocity: Smoother<f32>,
+    /// Draws the analog slop's random per-note offsets.
+    slop_rng: util::Rng,
+    /// The active note's random detune in semitones, and the factor its amp envelope times are
+    /// scaled by. Both are drawn on note on and are neutral without any analog slop.
+    slop_detune: f32,
+    slop_time_scale: f32,
+    /// The notes currently held down. When the sounding note is released we fall back to the
+    /// previously held one.
+    held_notes: NoteStack,
+    /// The note that was just let go of, while its release is held off in case it's played again.
+    pending_release: Option<PendingRelease>,
+    /// The number of samples since the last note on, used to scale the release by how long the
+    /// note was held.
+    note_held_samples: u32,
+    /// Whether the sounding note's key has been let go while the latch was on, so it's only still
+    /// sounding because of the latch.
+    latched: bool,
 
     oscillator: VariableSawOscillator,
-    filter: filter::Svf,
+    /// A sine an octave or two below the oscillator, mixed in before the amp envelope.
+    sub_oscillator: DigitalOscillator,
+    /// Mixed in alongside the oscillators, so it goes through the filter and amp envelope too.
+    noise: noise::Noise,
+    filter: filter::CascadedSvf,
     envelope: envelope::ADSR,
+    /// Bends the oscillator pitch at the start of a note. Only the attack and decay stages are
+    /// used, the sustain level is always zero.
+    pitch_envelope: envelope::ADSR,
+    /// Sweeps the filter cutoff. Triggered and released along with the amp envelope, but with its
+    /// own times so a fast filter sweep can sit on top of a long amp tail.
+    filter_envelope: envelope::ADSR,
+    /// A single vibrato LFO, so that every note is modulated in phase.
+    vibrato: lfo::Lfo,
+    /// Sits between the oscillator and the amp envelope, so a frozen voice is still shaped by the
+    /// envelope and filter.
+    freezer: freeze::Freezer,
+    bitcrusher: bitcrush::Bitcrusher,
+    tremolo: lfo::Lfo,
+    widener: widener::HaasWidener,
+    /// Captures the first output channel for the editor's oscilloscope, but only while the editor
+    /// has it enabled.
+    scope: scope::Scope,
+
+    /// The normalized offset the host is polyphonically modulating the voice's cutoff by, if it is.
+    /// Reset for every new voice.
+    cutoff_poly_mod: Option<f32>,
+    /// The voice's own modulated cutoff while `cutoff_poly_mod` is set, smoothed the same way as
+    /// the cutoff param.
+    cutoff_poly_mod_smoother: Smoother<f32>,
 }
 
 #[derive(Params)]
 struct TobyParams {
+    /// Where the editor was scrolled to and how far it was zoomed in. This isn't something that
+    /// should be automated, so it's stored alongside the parameters instead of being one.
+    #[persist = "editor_state"]
+    pub editor_state: RwLock<EditorState>,
+
     #[id = "gain"]
     pub gain: FloatParam,
 
+    /// Turns down the opposite channel, only has an effect with a stereo output.
+    #[id = "balance"]
+    pub balance: FloatParam,
+
+    /// Pans higher notes to the right and lower notes to the left, or the other way around for
+    /// negative amounts. Only has an effect with a stereo output.
+    #[id = "key_pan"]
+    pub key_pan: FloatParam,
+
     #[id = "cutoff"]
     pub cutoff: FloatParam,
 
     #[id = "resonance"]
     pub resonance: FloatParam,
 
+    #[id = "filter_mode"]
+    pub filter_mode: EnumParam<FilterMode>,
+
+    #[id = "filter_slope"]
+    pub filter_slope: EnumParam<FilterSlope>,
+
+    /// Turns the filter down as the resonance rises, so sweeping the resonance doesn't make
+    /// everything louder.
+    #[id = "res_comp"]
+    pub resonance_compensation: FloatParam,
+
+    /// How much the morph control also raises the filter resonance, so one control changes both
+    /// the oscillator's timbre and the filter's bite.
+    #[id = "morph_to_res"]
+    pub morph_to_resonance: FloatParam,
+
+    /// Sets the cutoff to the played note's pitch instead of using the cutoff param, so a self
+    /// oscillating filter can be played like a sine oscillator. This also bypasses the keytracking
+    /// and the filter envelope.
+    #[id = "filter_key_track"]
+    pub filter_tracks_keyboard: BoolParam,
+
+    /// How closely the cutoff follows the played note, relative to `KEYTRACK_REFERENCE_FREQUENCY`.
+    /// At 100% the cutoff doubles for every octave up, so the filter sounds equally bright across
+    /// the keyboard. Like `filter_tracks_keyboard` this follows the note itself, not the pitch
+    /// envelope or vibrato.
+    #[id = "keytrack"]
+    pub keytrack: FloatParam,
+
+    /// How many octaves the filter envelope sweeps the filter cutoff by at its peak. Negative
+    /// amounts close the filter instead.
+    #[id = "env_to_cutoff"]
+    pub env_to_cutoff: FloatParam,
+
+    #[id = "filter_env_atk"]
+    pub filter_env_attack: FloatParam,
+
+    #[id = "filter_env_dec"]
+    pub filter_env_decay: FloatParam,
+
+    #[id = "filter_env_sus"]
+    pub filter_env_sustain: FloatParam,
+
+    #[id = "filter_env_rel"]
+    pub filter_env_release: FloatParam,
+
+    /// Boosts the filter's input as the cutoff is lowered, so closing the filter doesn't make
+    /// everything so much quieter. Only applies to the low pass mode.
+    #[id = "filter_makeup"]
+    pub filter_makeup: FloatParam,
+
+    /// Drives the oscillator into a soft saturation before the filter, like the input stage of an
+    /// analog filter. The filter then shapes the added harmonics.
+    #[id = "pre_filter_drive"]
+    pub pre_filter_drive: FloatParam,
+
+    /// Saturates the filter's resonance, so high resonance growls instead of just getting louder.
+    #[id = "filter_drive"]
+    pub filter_drive: FloatParam,
+
+    /// Whether the filter comes before or after the amp envelope.
+    #[id = "filter_routing"]
+    pub filter_routing: EnumParam<FilterRouting>,
+
     #[id = "shape"]
     pub shape: FloatParam,
 
     #[id = "morph"]
     pub morph: FloatParam,
+
+    /// How much of the oscillator's band-limiting is applied. Lower values let the oscillator
+    /// alias for a grittier, lo-fi sound.
+    #[id = "antialias"]
+    pub antialias: FloatParam,
+
+    /// Gives every note a slightly different pitch, start phase and envelope timing, like an analog
+    /// synth that never plays the same note twice. At 0% every note is identical.
+    #[id = "analog_slop"]
+    pub analog_slop: FloatParam,
+
+    /// The level of the sub oscillator, a sine below the main oscillator for thicker basses.
+    #[id = "sub_level"]
+    pub sub_level: FloatParam,
+
+    #[id = "sub_octave"]
+    pub sub_octave: EnumParam<SubOctave>,
+
+    /// The level of the noise mixed in with the oscillators, for breathy and percussive sounds.
+    #[id = "noise_level"]
+    pub noise_level: FloatParam,
+
+    #[id = "noise_color"]
+    pub noise_color: EnumParam<NoiseColor>,
+
+    #[id = "env_mode"]
+    pub env_mode: EnumParam<GateMode>,
+
+    /// Lets the amp envelope keep cycling while the note is held.
+    #[id = "env_loop"]
+    pub env_loop: EnumParam<EnvelopeMode>,
+
+    /// The stage the amp and filter envelopes restart from when a note is played over one that's
+    /// still attacking or releasing.
+    #[id = "env_retrigger"]
+    pub env_retrigger: EnumParam<RetriggerStage>,
+
+    #[id = "env_attack"]
+    pub env_attack: FloatParam,
+
+    /// How long the amp envelope stays at its peak before decaying.
+    #[id = "env_hold"]
+    pub env_hold: FloatParam,
+
+    #[id = "env_decay"]
+    pub env_decay: FloatParam,
+
+    /// The level the amp envelope's decay stops at before its second decay, if it has one.
+    #[id = "env_break_level"]
+    pub env_break_level: FloatParam,
+
+    /// A second decay from the break level to the sustain level. At 0 ms the decay goes straight
+    /// to the sustain level.
+    #[id = "env_decay_2"]
+    pub env_decay_2: FloatParam,
+
+    #[id = "env_sustain"]
+    pub env_sustain: FloatParam,
+
+    #[id = "env_release"]
+    pub env_release: FloatParam,
+
+    /// Lets the amp envelope's attack overshoot by this much before settling, for a more
+    /// percussive start to every note.
+    #[id = "punch"]
+    pub punch: FloatParam,
+
+    /// How much the note's velocity affects its level, from not at all to fully.
+    #[id = "velocity_sensitivity"]
+    pub velocity_sensitivity: FloatParam,
+
+    /// Bends the amp envelope's ramps from linear to exponential.
+    #[id = "env_curve"]
+    pub env_curve: FloatParam,
+
+    /// Shortens the amp envelope's release for notes that are only held briefly, so staccato
+    /// notes stop short while held notes ring out.
+    #[id = "hold_scaled_release"]
+    pub hold_scaled_release: BoolParam,
+
+    /// Keeps notes sounding after they're released, until the next note is played or the latch is
+    /// turned off. Useful for drones.
+    #[id = "latch"]
+    pub latch: BoolParam,
+
+    /// How far the pitch envelope bends the note at its peak, in semitones.
+    #[id = "pitch_env_amt"]
+    pub pitch_env_amount: FloatParam,
+
+    #[id = "pitch_env_atk"]
+    pub pitch_env_attack: FloatParam,
+
+    #[id = "pitch_env_dec"]
+    pub pitch_env_decay: FloatParam,
+
+    #[id = "vibrato_rate"]
+    pub vibrato_rate: FloatParam,
+
+    /// How far the vibrato bends the note in either direction, in semitones.
+    #[id = "vibrato_depth"]
+    pub vibrato_depth: FloatParam,
+
+    /// Loops the last fraction of a second of the oscillator instead of playing it live.
+    #[id = "freeze"]
+    pub freeze: BoolParam,
+
+    /// The bitcrusher's bit depth. At the maximum the signal isn't quantized at all.
+    #[id = "crush_bits"]
+    pub crush_bits: FloatParam,
+
+    /// Holds every sample for this many samples to reduce the sample rate.
+    #[id = "crush_downsample"]
+    pub crush_downsample: FloatParam,
+
+    /// How much of the bitcrushed signal is blended in with the uncrushed signal.
+    #[id = "crush_mix"]
+    pub crush_mix: FloatParam,
+
+    /// How far the tremolo dips the output level, where 100% fully silences it at the LFO's trough.
+    #[id = "tremolo_depth"]
+    pub tremolo_depth: FloatParam,
+
+    #[id = "tremolo_rate"]
+    pub tremolo_rate: FloatParam,
+
+    #[id = "tremolo_shape"]
+    pub tremolo_shape: EnumParam<LfoShape>,
+
+    /// Sync the tremolo's rate to the host's tempo using the note division instead of the rate.
+    #[id = "tremolo_sync"]
+    pub tremolo_sync: BoolParam,
+
+    #[id = "tremolo_division"]
+    pub tremolo_division: EnumParam<NoteDivision>,
+
+    /// Delays the right channel by this many milliseconds to widen the stereo image.
+    #[id = "haas_delay"]
+    pub haas_delay: FloatParam,
+
+    /// Blends the whole effects chain (bitcrusher, tremolo and widener) with the dry voice. The
+    /// effects keep running at 0%, so turning this back up doesn't restart them.
+    #[id = "fx_mix"]
+    pub fx_mix: FloatParam,
+}
+
+/// The last held note, which was let go of but hasn't released the voice yet.
+struct PendingRelease {
+    note: u8,
+    /// The number of samples since the note was let go of.
+    age: u32,
+}
+
+/// Editor view settings that are saved with the plugin's state.
+#[derive(Serialize, Deserialize)]
+struct EditorState {
+    /// The vertical scroll offset in logical pixels.
+    pub scroll: f32,
+    /// The zoom factor, where 1.0 is the editor's default size.
+    pub zoom: f32,
+}
+
+impl Default for EditorState {
+    fn default() -> Self {
+        Self {
+            scroll: 0.0,
+            zoom: 1.0,
+        }
+    }
 }
 
 impl Default for Toby {
@@ -53,16 +424,44 @@ impl Default for Toby {
         Self {
             params: Arc::new(TobyParams::default()),
             sample_rate: 1.0,
+            rng_seed: DEFAULT_RNG_SEED,
+            bypass_smoothing: false,
 
             phase: 0.0,
 
             midi_note_id: 0,
             midi_note_freq: 1.0,
+            midi_note_channel: 0,
+            midi_note_voice_id: None,
             midi_note_gain: Smoother::new(SmoothingStyle::Linear(5.0)),
+            midi_note_velocity: Smoother::new(SmoothingStyle::Linear(VELOCITY_SMOOTHING_MS)),
+            slop_rng: util::Rng::new(DEFAULT_RNG_SEED),
+            slop_detune: 0.0,
+            slop_time_scale: 1.0,
+            held_notes: NoteStack::default(),
+            pending_release: None,
+            note_held_samples: 0,
+            latched: false,
 
             oscillator: VariableSawOscillator::default(),
-            filter: filter::Svf::default(),
+            sub_oscillator: DigitalOscillator::new(Shape::Sine),
+            noise: noise::Noise::default(),
+            filter: filter::CascadedSvf::default(),
             envelope: envelope::ADSR::default(),
+            pitch_envelope: envelope::ADSR {
+                sustain: 0.0,
+                ..envelope::ADSR::default()
+            },
+            filter_envelope: envelope::ADSR::default(),
+            vibrato: lfo::Lfo::default(),
+            freezer: freeze::Freezer::default(),
+            bitcrusher: bitcrush::Bitcrusher::default(),
+            tremolo: lfo::Lfo::default(),
+            widener: widener::HaasWidener::default(),
+            scope: scope::Scope::default(),
+
+            cutoff_poly_mod: None,
+            cutoff_poly_mod_smoother: Smoother::new(SmoothingStyle::Linear(10.0)),
         }
     }
 }
@@ -70,6 +469,8 @@ impl Default for Toby {
 impl Default for TobyParams {
     fn default() -> Self {
         Self {
+            editor_state: RwLock::new(EditorState::default()),
+
             gain: FloatParam::new(
                 "Gain",
                 -10.0,
@@ -81,17 +482,41 @@ impl Default for TobyParams {
             .with_smoother(SmoothingStyle::Linear(3.0))
             .with_step_size(0.01)
             .with_unit(" dB"),
+            balance: FloatParam::new(
+                "Balance",
+                0.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_value_to_string(formatters::v2s_f32_panning())
+            .with_string_to_value(formatters::s2v_f32_panning()),
+            key_pan: FloatParam::new(
+                "Key Pan",
+                0.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
 
             cutoff: FloatParam::new(
                 "Filter Cutoff",
                 20_000.0,
                 FloatRange::Skewed {
-                    min: 1.0,
-                    max: 20_000.0,
+                    min: MIN_CUTOFF,
+                    max: MAX_CUTOFF,
                     factor: FloatRange::skew_factor(-2.0),
                 },
             )
             .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_poly_modulation_id(CUTOFF_POLY_MOD_ID)
             // We purposely don't specify a step size here, but the parameter should still be
             // displayed as if it were rounded. This formatter also includes the unit.
             .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
@@ -101,18 +526,565 @@ impl Default for TobyParams {
                 0.5,
                 FloatRange::Linear {
                     min: 0.01,
-                    max: 100.0,
+                    max: MAX_RESONANCE,
                 },
             )
             .with_smoother(SmoothingStyle::Linear(3.0)),
+            filter_mode: EnumParam::new("Filter Mode", FilterMode::LowPass),
+            filter_slope: EnumParam::new("Filter Slope", FilterSlope::Db12),
+            resonance_compensation: FloatParam::new(
+                "Resonance Compensation",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            morph_to_resonance: FloatParam::new(
+                "Morph to Resonance",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            filter_tracks_keyboard: BoolParam::new("Filter Keyboard Tracking", false),
+            keytrack: FloatParam::new("Key Track", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(10.0))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            env_to_cutoff: FloatParam::new(
+                "Env to Cutoff",
+                0.0,
+                FloatRange::Linear {
+                    min: -MAX_ENV_TO_CUTOFF_OCTAVES,
+                    max: MAX_ENV_TO_CUTOFF_OCTAVES,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01)
+            .with_unit(" oct"),
+            filter_env_attack: FloatParam::new(
+                "Filter Env Attack",
+                10.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(ENVELOPE_TIME_SMOOTHING_MS))
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            filter_env_decay: FloatParam::new(
+                "Filter Env Decay",
+                300.0,
+                FloatRange::Skewed {
+                    min: 1.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(ENVELOPE_TIME_SMOOTHING_MS))
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            filter_env_sustain: FloatParam::new(
+                "Filter Env Sustain",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            filter_env_release: FloatParam::new(
+                "Filter Env Release",
+                100.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(ENVELOPE_TIME_SMOOTHING_MS))
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            filter_makeup: FloatParam::new(
+                "Filter Makeup",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            filter_routing: EnumParam::new("Filter Routing", FilterRouting::PostEnvelope),
+            pre_filter_drive: FloatParam::new(
+                "Pre-Filter Drive",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            filter_drive: FloatParam::new(
+                "Filter Drive",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
             shape: FloatParam::new("Shape", 0.5, FloatRange::Linear { min: 0.0, max: 1.0 })
                 .with_smoother(SmoothingStyle::Linear(3.0))
                 .with_step_size(0.01),
             morph: FloatParam::new("Morph", 0.2, FloatRange::Linear { min: 0.0, max: 1.0 })
                 .with_smoother(SmoothingStyle::Linear(3.0))
                 .with_step_size(0.01),
+            antialias: FloatParam::new(
+                "Antialiasing",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            analog_slop: FloatParam::new(
+                "Analog Slop",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            sub_level: FloatParam::new("Sub Level", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(10.0))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            sub_octave: EnumParam::new("Sub Octave", SubOctave::OneDown),
+            noise_level: FloatParam::new(
+                "Noise Level",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            noise_color: EnumParam::new("Noise Color", NoiseColor::White),
+
+            env_mode: EnumParam::new("Envelope Mode", GateMode::Gate),
+            env_loop: EnumParam::new("Envelope Loop", EnvelopeMode::OneShot),
+            env_retrigger: EnumParam::new("Retrigger Stage", RetriggerStage::Attack),
+            env_attack: FloatParam::new(
+                "Attack",
+                50.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(ENVELOPE_TIME_SMOOTHING_MS))
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            env_hold: FloatParam::new(
+                "Hold",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 2000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(ENVELOPE_TIME_SMOOTHING_MS))
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            env_decay: FloatParam::new(
+                "Decay",
+                1.0,
+                FloatRange::Skewed {
+                    min: 1.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(ENVELOPE_TIME_SMOOTHING_MS))
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            env_break_level: FloatParam::new(
+                "Break Level",
+                0.5,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            env_decay_2: FloatParam::new(
+                "Decay 2",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(ENVELOPE_TIME_SMOOTHING_MS))
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            env_sustain: FloatParam::new("Sustain", 0.8, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(10.0))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            env_release: FloatParam::new(
+                "Release",
+                100.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(ENVELOPE_TIME_SMOOTHING_MS))
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            punch: FloatParam::new("Punch", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            velocity_sensitivity: FloatParam::new(
+                "Velocity Sensitivity",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            env_curve: FloatParam::new(
+                "Envelope Curve",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            hold_scaled_release: BoolParam::new("Hold Scaled Release", false),
+            latch: BoolParam::new("Latch", false),
+
+            pitch_env_amount: FloatParam::new(
+                "Pitch Env Amount",
+                0.0,
+                FloatRange::Linear {
+                    min: -48.0,
+                    max: 48.0,
+                },
+            )
+            .with_step_size(0.01)
+            .with_unit(" st"),
+            pitch_env_attack: FloatParam::new(
+                "Pitch Env Attack",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 1000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(ENVELOPE_TIME_SMOOTHING_MS))
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            pitch_env_decay: FloatParam::new(
+                "Pitch Env Decay",
+                100.0,
+                FloatRange::Skewed {
+                    min: 1.0,
+                    max: 2000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(ENVELOPE_TIME_SMOOTHING_MS))
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+
+            vibrato_rate: FloatParam::new(
+                "Vibrato Rate",
+                5.0,
+                FloatRange::Skewed {
+                    min: 0.1,
+                    max: 20.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_step_size(0.01)
+            .with_unit(" Hz"),
+            vibrato_depth: FloatParam::new(
+                "Vibrato Depth",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 2.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_step_size(0.01)
+            .with_unit(" st"),
+
+            freeze: BoolParam::new("Freeze", false),
+
+            crush_bits: FloatParam::new(
+                "Bit Depth",
+                bitcrush::MAX_BIT_DEPTH,
+                FloatRange::Linear {
+                    min: 1.0,
+                    max: bitcrush::MAX_BIT_DEPTH,
+                },
+            )
+            .with_step_size(0.01)
+            .with_unit(" bits"),
+            crush_downsample: FloatParam::new(
+                "Downsample",
+                1.0,
+                FloatRange::Skewed {
+                    min: 1.0,
+                    max: 32.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_step_size(0.01)
+            .with_unit("x"),
+            crush_mix: FloatParam::new("Crush Mix", 1.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_smoother(SmoothingStyle::Linear(10.0))
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+
+            tremolo_depth: FloatParam::new(
+                "Tremolo Depth",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            tremolo_rate: FloatParam::new(
+                "Tremolo Rate",
+                4.0,
+                FloatRange::Skewed {
+                    min: 0.1,
+                    max: 20.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_step_size(0.01)
+            .with_unit(" Hz"),
+            tremolo_shape: EnumParam::new("Tremolo Shape", LfoShape::Sine),
+            tremolo_sync: BoolParam::new("Tremolo Sync", false),
+            tremolo_division: EnumParam::new("Tremolo Division", NoteDivision::Eighth),
+
+            haas_delay: FloatParam::new(
+                "Haas Delay",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: widener::MAX_DELAY_MS,
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(50.0))
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+
+            fx_mix: FloatParam::new(
+                "Effects Mix",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_smoother(SmoothingStyle::Linear(10.0))
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+}
+
+/// The note events going into and coming out of a block. This is the host's process context when
+/// running as a plugin, and a plain queue in the tests.
+trait NoteEvents {
+    fn next_event(&mut self) -> Option<PluginNoteEvent<Toby>>;
+    fn send_event(&mut self, event: PluginNoteEvent<Toby>);
+}
+
+impl<C: ProcessContext<Toby>> NoteEvents for C {
+    fn next_event(&mut self) -> Option<PluginNoteEvent<Toby>> {
+        ProcessContext::next_event(self)
+    }
+
+    fn send_event(&mut self, event: PluginNoteEvent<Toby>) {
+        ProcessContext::send_event(self, event)
+    }
+}
+
+impl Toby {
+    /// Set up for a new sample rate and processing mode. Everything that's sized for the sample
+    /// rate keeps its contents if the sample rate didn't change.
+    fn set_buffer_config(&mut self, buffer_config: &BufferConfig) {
+        self.sample_rate = buffer_config.sample_rate;
+        self.set_smoothing_bypassed(matches!(buffer_config.process_mode, ProcessMode::Offline));
+        self.freezer.resize(buffer_config.sample_rate);
+        self.widener.resize(buffer_config.sample_rate);
+    }
+
+    /// Turn all smoothing off or back on. Offline renders turn it off so they're reproducible, and
+    /// so do the tests so they can check exact sample values.
+    fn set_smoothing_bypassed(&mut self, bypassed: bool) {
+        self.bypass_smoothing = bypassed;
+        self.oscillator.smooth_frequency = !bypassed;
+    }
+
+    /// Snap every parameter's smoother to the parameter's current value. With sample accurate
+    /// automation the host splits the buffer at every parameter change, so doing this at the start
+    /// of every block means the parameter smoothers never ramp.
+    fn snap_param_smoothers(&self) {
+        // Every float param is listed here, including the ones without a smoother, so giving one a
+        // smoother later doesn't also require remembering to add it here
+        let params = &self.params;
+        for param in [
+            &params.gain,
+            &params.balance,
+            &params.key_pan,
+            &params.cutoff,
+            &params.resonance,
+            &params.resonance_compensation,
+            &params.morph_to_resonance,
+            &params.keytrack,
+            &params.env_to_cutoff,
+            &params.filter_env_attack,
+            &params.filter_env_decay,
+            &params.filter_env_sustain,
+            &params.filter_env_release,
+            &params.filter_makeup,
+            &params.pre_filter_drive,
+            &params.filter_drive,
+            &params.shape,
+            &params.morph,
+            &params.antialias,
+            &params.analog_slop,
+            &params.sub_level,
+            &params.noise_level,
+            &params.env_attack,
+            &params.env_hold,
+            &params.env_decay,
+            &params.env_break_level,
+            &params.env_decay_2,
+            &params.env_sustain,
+            &params.env_release,
+            &params.punch,
+            &params.velocity_sensitivity,
+            &params.env_curve,
+            &params.pitch_env_amount,
+            &params.pitch_env_attack,
+            &params.pitch_env_decay,
+            &params.vibrato_rate,
+            &params.vibrato_depth,
+            &params.crush_bits,
+            &params.crush_downsample,
+            &params.crush_mix,
+            &params.tremolo_depth,
+            &params.tremolo_rate,
+            &params.haas_delay,
+            &params.fx_mix,
+        ] {
+            param.smoothed.reset(param.value());
+        }
+    }
+
+    /// Seed every randomized feature from `seed` instead of `DEFAULT_RNG_SEED`. This takes effect on
+    /// the next reset, which restarts all of them from the start of their streams.
+    pub fn set_rng_seed(&mut self, seed: u32) {
+        self.rng_seed = seed;
+    }
+
+    /// Start ramping one of the smoothers driven by note events to `target`, or jump straight to
+    /// it while smoothing is bypassed.
+    fn set_smoother_target(&self, smoother: &Smoother<f32>, target: f32) {
+        if self.bypass_smoothing {
+            smoother.reset(target);
+        } else {
+            smoother.set_target(self.sample_rate, target);
         }
     }
+
+    /// The filter resonance after the morph to resonance routing, which can't push it past
+    /// `MAX_RESONANCE`.
+    fn filter_resonance(resonance: f32, morph: f32, morph_to_resonance: f32) -> f32 {
+        (resonance * 2.0f32.powf(morph * morph_to_resonance * MORPH_TO_RESONANCE_OCTAVES))
+            .min(MAX_RESONANCE)
+    }
+
+    /// Tell the host the active voice is gone, so it stops sending polyphonic modulation for it,
+    /// and drop that modulation.
+    fn terminate_voice(&mut self, timing: u32, events: &mut impl NoteEvents) {
+        if let Some(voice_id) = self.midi_note_voice_id.take() {
+            events.send_event(NoteEvent::VoiceTerminated {
+                timing,
+                voice_id: Some(voice_id),
+                channel: self.midi_note_channel,
+                note: self.midi_note_id,
+            });
+        }
+        self.cutoff_poly_mod = None;
+    }
+
+    /// Boost `v` into a soft saturation by the pre-filter drive amount `drive`, in `[0, 1]`.
+    fn pre_filter_drive(v: f32, drive: f32) -> f32 {
+        // The soft limiter isn't linear past full scale, and the oscillator's peaks can already go
+        // past that, so the saturation is skipped entirely without any drive to keep it
+        // transparent
+        if drive > 0.0 {
+            util::soft_limit(v * util::db_to_gain_fast(drive * MAX_PRE_FILTER_DRIVE_DB))
+        } else {
+            v
+        }
+    }
+
+    /// Let go of the sounding note `note`. Monophonic playing falls back to the most recently
+    /// pressed note that's still held, and the voice is only released once no notes are held.
+    fn release_note(&mut self, note: u8, timing: u32, events: &mut impl NoteEvents) {
+        match self.held_notes.last() {
+            Some(previous_note) => {
+                // The host's voice for the note being fallen back to ended when it was taken
+                // over, so the fallback plays on without a voice ID
+                self.terminate_voice(timing, events);
+                self.midi_note_id = previous_note;
+                self.midi_note_freq = util::midi_note_to_freq(previous_note);
+            }
+            None => {
+                if self.params.hold_scaled_release.value() {
+                    let held_time = self.note_held_samples as f32 / self.sample_rate;
+                    self.envelope.release_scale =
+                        (held_time / FULL_RELEASE_HOLD_TIME).clamp(MIN_HOLD_RELEASE_SCALE, 1.0);
+                }
+
+                if self.envelope.gate_mode == GateMode::Gate {
+                    self.pending_release = Some(PendingRelease { note, age: 0 });
+                } else {
+                    self.start_release();
+                }
+            }
+        }
+    }
+
+    /// Release the voice now that no notes are held anymore.
+    fn start_release(&mut self) {
+        self.set_smoother_target(&self.midi_note_gain, 0.0);
+        self.envelope.trigger(envelope::EnvelopeEvent::Release);
+        self.filter_envelope
+            .trigger(envelope::EnvelopeEvent::Release);
+    }
 }
 
 impl Plugin for Toby {
@@ -153,7 +1125,7 @@ impl Plugin for Toby {
         buffer_config: &BufferConfig,
         _context: &mut impl InitContext<Self>,
     ) -> bool {
-        self.sample_rate = buffer_config.sample_rate;
+        self.set_buffer_config(buffer_config);
 
         true
     }
@@ -162,7 +1134,30 @@ impl Plugin for Toby {
         self.phase = 0.0;
         self.midi_note_id = 0;
         self.midi_note_freq = 1.0;
+        self.midi_note_voice_id = None;
+        self.cutoff_poly_mod = None;
+        self.held_notes.clear();
+        self.pending_release = None;
+        self.note_held_samples = 0;
+        self.latched = false;
+        self.midi_note_velocity.reset(1.0);
+        self.filter
+            .reseed_noise(util::stream_seed(self.rng_seed, FILTER_NOISE_STREAM));
+        self.slop_rng = util::Rng::new(util::stream_seed(self.rng_seed, SLOP_STREAM));
+        self.slop_detune = 0.0;
+        self.slop_time_scale = 1.0;
         self.envelope.reset();
+        self.filter_envelope.reset();
+        self.pitch_envelope.reset();
+        self.vibrato.reset();
+        self.sub_oscillator.reset();
+        self.noise
+            .reset(util::stream_seed(self.rng_seed, NOISE_STREAM));
+        self.freezer.reset();
+        self.bitcrusher.reset();
+        self.tremolo.reset();
+        self.widener.reset();
+        self.scope.reset();
     }
 
     fn process(
@@ -171,11 +1166,91 @@ impl Plugin for Toby {
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        let mut next_event = context.next_event();
-        for (sample_id, channel_samples) in buffer.iter_samples().enumerate() {
+        let tempo = context.transport().tempo;
+        self.process_block(buffer.as_slice(), tempo, context)
+    }
+}
+
+impl Toby {
+    /// Render one block of output, with one slice per output channel. This is all of `process()`
+    /// except for talking to the host, so the tests can render blocks too.
+    fn process_block(
+        &mut self,
+        output: &mut [&mut [f32]],
+        tempo: Option<f64>,
+        events: &mut impl NoteEvents,
+    ) -> ProcessStatus {
+        if self.bypass_smoothing {
+            self.snap_param_smoothers();
+        }
+
+        // Without a tempo from the host the tremolo falls back to its free running rate
+        let tremolo_rate = match tempo {
+            Some(tempo) if self.params.tremolo_sync.value() => {
+                self.params.tremolo_division.value().frequency(tempo as f32)
+            }
+            _ => self.params.tremolo_rate.value(),
+        };
+
+        let is_stereo = output.len() == 2;
+        let num_samples = output.first().map_or(0, |channel| channel.len());
+
+        if self.latched && !self.params.latch.value() {
+            self.latched = false;
+            self.release_note(self.midi_note_id, 0, events);
+        }
+
+        let mut next_event = events.next_event();
+
+        // A capture left over from before the scope was disabled would be stale by the time it's
+        // enabled again
+        let capture_scope = self.scope.is_enabled();
+        if !capture_scope {
+            self.scope.reset();
+        }
+
+        // With no note playing and nothing left in the filter, the bitcrusher or the widener's
+        // delay line, every sample would come out as exactly zero. Idle instances skip the whole
+        // block and let the host suspend them until the next note arrives. A self-oscillating
+        // filter after the envelope keeps sounding on its own, including when the resonance was
+        // only turned up while idling.
+        let filter_is_silent = match self.params.filter_routing.value() {
+            FilterRouting::PreEnvelope => true,
+            FilterRouting::PostEnvelope => {
+                self.filter.is_silent()
+                    && !filter::self_oscillates(Self::filter_resonance(
+                        self.params.resonance.value(),
+                        self.params.morph.value(),
+                        self.params.morph_to_resonance.value(),
+                    ))
+            }
+        };
+        let is_idle = self.envelope.is_finished()
+            && filter_is_silent
+            && self.bitcrusher.is_silent()
+            && (!is_stereo || self.widener.is_silent());
+        if next_event.is_none() && is_idle {
+            for channel in output.iter_mut() {
+                channel.fill(0.0);
+            }
+
+            // Nothing is left ringing, so the host can suspend the plugin until the next event
+            return ProcessStatus::Normal;
+        }
+
+        for sample_id in 0..num_samples {
             // Smoothing is optionally built into the parameters themselves
             let gain = self.params.gain.smoothed.next();
 
+            self.note_held_samples = self.note_held_samples.saturating_add(1);
+            if let Some(pending) = &mut self.pending_release {
+                pending.age += 1;
+                if pending.age >= RETRIGGER_DEBOUNCE_SAMPLES {
+                    self.pending_release = None;
+                    self.start_release();
+                }
+            }
+
             // self.oscillator.waveshape =
 
             // This plugin can be either triggered by MIDI or controleld by a parameter
@@ -187,64 +1262,323 @@ impl Plugin for Toby {
                     }
 
                     match event {
-                        NoteEvent::NoteOn { note, velocity, .. } => {
+                        NoteEvent::NoteOn {
+                            voice_id,
+                            channel,
+                            note,
+                            velocity,
+                            ..
+                        } => {
+                            // Playing the note that was just let go of again cancels its release.
+                            // Any other note comes after the release like it normally would.
+                            let replayed = match self.pending_release.take() {
+                                Some(pending) if pending.note == note => true,
+                                Some(_) => {
+                                    self.start_release();
+                                    false
+                                }
+                                None => false,
+                            };
+
+                            // The new note takes over the voice, so the host should stop
+                            // modulating the old one
+                            self.terminate_voice(sample_id as u32, events);
+                            self.midi_note_voice_id = voice_id;
+                            self.midi_note_channel = channel;
+                            self.scope.note_on();
+
+                            self.held_notes.push(note);
                             self.midi_note_id = note;
                             self.midi_note_freq = util::midi_note_to_freq(note);
-                            self.midi_note_gain.set_target(self.sample_rate, velocity);
+                            self.set_smoother_target(&self.midi_note_gain, velocity);
+                            // A note played from silence starts at its own velocity straight away
+                            let from_silence = self.envelope.is_finished();
+                            if from_silence {
+                                self.midi_note_velocity.reset(velocity);
+                            } else {
+                                self.set_smoother_target(&self.midi_note_velocity, velocity);
+                            }
+                            // All offsets are always drawn, so changing the slop amount doesn't
+                            // change which random values later notes get. The phase can only jump
+                            // while there's nothing to hear.
+                            let slop = self.params.analog_slop.value();
+                            self.slop_detune =
+                                self.slop_rng.next_bipolar() * slop * MAX_SLOP_DETUNE_CENTS / 100.0;
+                            self.slop_time_scale =
+                                1.0 + self.slop_rng.next_bipolar() * slop * MAX_SLOP_TIME_JITTER;
+                            let phase_offset = self.slop_rng.next_unipolar() * slop;
+                            if from_silence {
+                                self.oscillator.offset_phase(phase_offset);
+                            }
+                            self.pitch_envelope.trigger(envelope::EnvelopeEvent::Attack);
+                            self.note_held_samples = 0;
+                            self.latched = false;
+                            self.envelope.release_scale = 1.0;
 
-                            match self.envelope.stage {
-                                EnvelopeStage::Attack | EnvelopeStage::Release => {
-                                    self.envelope.trigger(envelope::EnvelopeEvent::Attack)
-                                    log!("")
-                                }
-                                EnvelopeStage::Decay | EnvelopeStage::Sustain => {
-                                    self.envelope.timer = 0.0;
-                                }
+                            // A replayed note restarts its attack from wherever the envelopes are,
+                            // since the voice was never released
+                            if replayed {
+                                self.envelope.trigger(envelope::EnvelopeEvent::Attack);
+                                self.filter_envelope
+                                    .trigger(envelope::EnvelopeEvent::Attack);
+                            } else {
+                                let retrigger_stage = self.params.env_retrigger.value();
+                                self.envelope.retrigger(retrigger_stage);
+                                self.filter_envelope.retrigger(retrigger_stage);
                             }
                         }
-                        NoteEvent::NoteOff { note, .. } if note == self.midi_note_id => {
-                            self.midi_note_gain.set_target(self.sample_rate, 0.0);
-                            self.envelope.trigger(envelope::EnvelopeEvent::Release)
+                        NoteEvent::NoteOff { note, .. } => {
+                            self.held_notes.remove(note);
+
+                            // Releasing a note other than the sounding one only updates the stack,
+                            // so a fast trill always ends up on the right note. A latched note keeps
+                            // sounding until the next note on or until the latch is turned off.
+                            if note == self.midi_note_id {
+                                if self.params.latch.value() {
+                                    self.latched = true;
+                                } else {
+                                    self.release_note(note, sample_id as u32, events);
+                                }
+                            }
                         }
                         NoteEvent::PolyPressure { note, pressure, .. }
                             if note == self.midi_note_id =>
                         {
-                            self.midi_note_gain.set_target(self.sample_rate, pressure);
+                            self.set_smoother_target(&self.midi_note_gain, pressure);
+                        }
+                        NoteEvent::PolyModulation {
+                            voice_id,
+                            poly_modulation_id: CUTOFF_POLY_MOD_ID,
+                            normalized_offset,
+                            ..
+                        } if Some(voice_id) == self.midi_note_voice_id => {
+                            let target = self.params.cutoff.preview_modulated(normalized_offset);
+                            // The host sends the initial modulation right after the note starts,
+                            // so there's nothing to smooth from yet
+                            if self.cutoff_poly_mod.is_some() {
+                                self.set_smoother_target(&self.cutoff_poly_mod_smoother, target);
+                            } else {
+                                self.cutoff_poly_mod_smoother.reset(target);
+                            }
+                            self.cutoff_poly_mod = Some(normalized_offset);
+                        }
+                        NoteEvent::MonoAutomation {
+                            poly_modulation_id: CUTOFF_POLY_MOD_ID,
+                            normalized_value,
+                            ..
+                        } => {
+                            // The cutoff param itself has already been updated, but a modulated
+                            // voice needs its own target recomputed from the new base value
+                            if let Some(offset) = self.cutoff_poly_mod {
+                                let target =
+                                    self.params.cutoff.preview_plain(normalized_value + offset);
+                                self.set_smoother_target(&self.cutoff_poly_mod_smoother, target);
+                            }
                         }
                         _ => (),
                     }
 
-                    next_event = context.next_event();
+                    next_event = events.next_event();
                 }
 
                 // This gain envelope prevents clicks with new notes and with released notes
                 let morph = self.params.morph.smoothed.next();
 
-                let saw_pw = if morph < 0.5 {
-                    morph + 0.5
-                } else {
-                    1.0 - (morph - 0.5) * 2.0
-                };
+                let (saw_pw, saw_shape) = oscillator::analog::morph_to_pw_and_waveshape(morph);
+
+                self.pitch_envelope.attack = self.params.pitch_env_attack.smoothed.next() / 1000.0;
+                self.pitch_envelope.decay = self.params.pitch_env_decay.smoothed.next() / 1000.0;
+                let pitch_env_semitones = self.params.pitch_env_amount.value()
+                    * self.pitch_envelope.next(self.sample_rate);
+                let vibrato_semitones = self.params.vibrato_depth.smoothed.next()
+                    * self.vibrato.next(
+                        LfoShape::Sine,
+                        self.params.vibrato_rate.value(),
+                        self.sample_rate,
+                    );
+                let frequency = self.midi_note_freq
+                    * 2.0f32
+                        .powf((pitch_env_semitones + vibrato_semitones + self.slop_detune) / 12.0);
 
-                let saw_pw = (saw_pw * 1.1).clamp(0.005, 1.0);
-                let saw_shape = (10.0 - 21.0 * morph).clamp(0.0, 1.0);
+                self.oscillator.prepare(
+                    saw_pw,
+                    saw_shape,
+                    self.params.antialias.value(),
+                    frequency,
+                    self.sample_rate,
+                );
+                let v = self.oscillator.process();
+                // The sub follows the pitch envelope and vibrato too, so it stays in tune with the
+                // oscillator
+                let sub = self.sub_oscillator.process(
+                    frequency * self.params.sub_octave.value().ratio(),
+                    self.sample_rate,
+                );
+                let v = v + sub * self.params.sub_level.smoothed.next();
+                let noise = self.noise.next(self.params.noise_color.value());
+                let v = v + noise * self.params.noise_level.smoothed.next();
+                let v = self.freezer.process(v, self.params.freeze.value());
+                self.envelope.gate_mode = self.params.env_mode.value();
+                self.envelope.mode = self.params.env_loop.value();
+                self.envelope.attack =
+                    self.params.env_attack.smoothed.next() / 1000.0 * self.slop_time_scale;
+                self.envelope.hold = self.params.env_hold.smoothed.next() / 1000.0;
+                self.envelope.decay =
+                    self.params.env_decay.smoothed.next() / 1000.0 * self.slop_time_scale;
+                self.envelope.break_level = self.params.env_break_level.smoothed.next();
+                self.envelope.decay_2 =
+                    self.params.env_decay_2.smoothed.next() / 1000.0 * self.slop_time_scale;
+                self.envelope.sustain = self.params.env_sustain.smoothed.next();
+                self.envelope.release =
+                    self.params.env_release.smoothed.next() / 1000.0 * self.slop_time_scale;
+                self.envelope.punch = self.params.punch.value();
+                self.envelope.curve = self.params.env_curve.value();
+                let velocity_gain = 1.0
+                    + (self.midi_note_velocity.next() - 1.0)
+                        * self.params.velocity_sensitivity.value();
+                let amp = self.envelope.next(self.sample_rate) * velocity_gain;
+                // Punch overshoots the envelope past 1.0, which could push the signal past full
+                // scale, so only that overshoot is saturated instead of clipped
+                let apply_amp = |v: f32| util::saturate_overshoot(v, amp);
+                let filter_routing = self.params.filter_routing.value();
+                let v = match filter_routing {
+                    FilterRouting::PreEnvelope => v,
+                    FilterRouting::PostEnvelope => apply_amp(v),
+                };
 
-                self.oscillator
-                    .prepare(saw_pw, saw_shape, self.midi_note_freq, self.sample_rate);
-                let v = self
-                    .oscillator
-                    .process(self.midi_note_freq, self.sample_rate);
-                let v = v * self.envelope.next(self.sample_rate);
+                if self.envelope.is_finished() && self.midi_note_voice_id.is_some() {
+                    self.terminate_voice(sample_id as u32, events);
+                }
 
                 let cutoff = self.params.cutoff.smoothed.next();
-                let resonance = self.params.resonance.smoothed.next();
+                let cutoff = match self.cutoff_poly_mod {
+                    Some(_) => self.cutoff_poly_mod_smoother.next(),
+                    None => cutoff,
+                };
+                // Both this and the keyboard tracking below follow the played note rather than the
+                // oscillator's modulated frequency, so vibrato and the pitch envelope don't make
+                // the filter wobble along with them
+                let keytrack = self.params.keytrack.smoothed.next();
+                let cutoff =
+                    cutoff * (self.midi_note_freq / KEYTRACK_REFERENCE_FREQUENCY).powf(keytrack);
+                self.filter_envelope.attack =
+                    self.params.filter_env_attack.smoothed.next() / 1000.0;
+                self.filter_envelope.decay = self.params.filter_env_decay.smoothed.next() / 1000.0;
+                self.filter_envelope.sustain = self.params.filter_env_sustain.smoothed.next();
+                self.filter_envelope.release =
+                    self.params.filter_env_release.smoothed.next() / 1000.0;
+                // The filter envelope follows the amp envelope's gate mode, so one-shots sweep
+                // the filter all the way through too
+                self.filter_envelope.gate_mode = self.envelope.gate_mode;
+                let filter_env = self.filter_envelope.next(self.sample_rate);
+                let cutoff =
+                    cutoff * 2.0f32.powf(filter_env * self.params.env_to_cutoff.smoothed.next());
+                // A self-oscillating filter sings right at its cutoff, so this plays it at the
+                // note's pitch. The keytracking and the filter envelope would only detune it.
+                let cutoff = if self.params.filter_tracks_keyboard.value() {
+                    self.midi_note_freq
+                } else {
+                    cutoff
+                }
+                .clamp(MIN_CUTOFF, MAX_CUTOFF);
+                let resonance = Self::filter_resonance(
+                    self.params.resonance.smoothed.next(),
+                    morph,
+                    self.params.morph_to_resonance.smoothed.next(),
+                );
+
+                // Only the low pass loses more of the signal the lower the cutoff goes. The other
+                // modes keep or lose about as much wherever the cutoff is.
+                let makeup = self.params.filter_makeup.smoothed.next();
+                let makeup_db = if self.params.filter_mode.value() == FilterMode::LowPass {
+                    (makeup * FILTER_MAKEUP_DB_PER_OCTAVE * (MAX_CUTOFF / cutoff).log2())
+                        .min(MAX_FILTER_MAKEUP_DB)
+                } else {
+                    0.0
+                };
+
+                let v = Self::pre_filter_drive(v, self.params.pre_filter_drive.smoothed.next());
 
+                // Once the note has finished and the filter has rung out there's nothing left to
+                // hear, so the filter is cleared and skipped instead of slowly decaying towards
+                // denormals. A self-oscillating filter never rings out, so after the envelope it
+                // keeps running and sounding on its own. Before the envelope its output is
+                // silenced by the envelope anyway.
+                self.filter.mode = self.params.filter_mode.value();
+                self.filter.set_slope(self.params.filter_slope.value());
+                self.filter.drive = self.params.filter_drive.smoothed.next();
+                self.filter
+                    .set_resonance_compensation(self.params.resonance_compensation.value());
                 self.filter.set_f_q(cutoff / self.sample_rate, resonance);
-                self.filter.process(v)
+                let rung_out = match filter_routing {
+                    FilterRouting::PreEnvelope => true,
+                    FilterRouting::PostEnvelope => self.filter.is_silent(),
+                };
+                let v = if self.envelope.is_finished() && rung_out {
+                    self.filter.reset();
+                    0.0
+                } else {
+                    self.filter.process(v * util::db_to_gain_fast(makeup_db))
+                };
+
+                match filter_routing {
+                    FilterRouting::PreEnvelope => apply_amp(v),
+                    FilterRouting::PostEnvelope => v,
+                }
+            };
+
+            let dry = sine;
+
+            let crushed = self.bitcrusher.process(
+                sine,
+                self.params.crush_bits.value(),
+                self.params.crush_downsample.value(),
+            );
+            let sine = sine + (crushed - sine) * self.params.crush_mix.smoothed.next();
+
+            let tremolo = self.tremolo.next(
+                self.params.tremolo_shape.value(),
+                tremolo_rate,
+                self.sample_rate,
+            );
+            let sine =
+                sine * (1.0 - self.params.tremolo_depth.smoothed.next() * (1.0 - tremolo) * 0.5);
+
+            // The delay is smoothed and interpolated, so automating it sweeps the delay instead of
+            // making the right channel jump
+            let haas_delay_samples =
+                self.params.haas_delay.smoothed.next() / 1000.0 * self.sample_rate;
+            let delayed = if is_stereo {
+                self.widener.process(sine, haas_delay_samples)
+            } else {
+                sine
             };
 
-            for sample in channel_samples {
-                *sample = sine * util::db_to_gain_fast(gain);
+            let fx_mix = self.params.fx_mix.smoothed.next();
+            let sine = dry + (sine - dry) * fx_mix;
+            let delayed = dry + (delayed - dry) * fx_mix;
+
+            let balance = self.params.balance.smoothed.next();
+            // Like the balance this only ever turns the opposite side down, so a note panned all the
+            // way to one side is never louder than a centered one
+            let key_pan = (self.params.key_pan.smoothed.next()
+                * (self.midi_note_id as f32 - KEY_PAN_CENTER_NOTE)
+                / KEY_PAN_SPREAD_SEMITONES)
+                .clamp(-1.0, 1.0);
+            for (channel, samples) in output.iter_mut().enumerate() {
+                let sample = &mut samples[sample_id];
+                let (value, balance_gain) = match channel {
+                    0 if is_stereo => (sine, (1.0 - balance).min(1.0) * (1.0 - key_pan).min(1.0)),
+                    1 if is_stereo => {
+                        (delayed, (1.0 + balance).min(1.0) * (1.0 + key_pan).min(1.0))
+                    }
+                    _ => (sine, 1.0),
+                };
+
+                *sample = value * util::db_to_gain_fast(gain) * balance_gain;
+                if capture_scope && channel == 0 {
+                    self.scope.process(*sample);
+                }
             }
         }
 
@@ -256,6 +1590,11 @@ impl ClapPlugin for Toby {
     const CLAP_ID: &'static str = "com.abstractaudio.toby";
     const CLAP_DESCRIPTION: Option<&'static str> =
         Some("An optionally MIDI controlled sine test tone");
+    const CLAP_POLY_MODULATION_CONFIG: Option<PolyModulationConfig> = Some(PolyModulationConfig {
+        // Toby is monophonic, so there's only ever a single voice to modulate
+        max_voice_capacity: 1,
+        supports_overlapping_voices: false,
+    });
     const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
     const CLAP_SUPPORT_URL: Option<&'static str> = None;
     const CLAP_FEATURES: &'static [ClapFeature] = &[
@@ -278,7 +1617,917 @@ nih_export_vst3!(Toby);
 #[macro_export]
 macro_rules! log {
     ($($args:tt)*) => (
-        let mut f = unsafe { std::fs::File::from_raw_fd(2) };
+        let mut f = unsafe { <std::fs::File as std::os::fd::FromRawFd>::from_raw_fd(2) };
         _ = write!(f,$($args)*)
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use envelope::EnvelopeStage;
+    use std::collections::VecDeque;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// Feeds note events into `Toby::process_block()` and collects the ones it sends back.
+    #[derive(Default)]
+    struct TestEvents {
+        incoming: VecDeque<PluginNoteEvent<Toby>>,
+        sent: Vec<PluginNoteEvent<Toby>>,
+    }
+
+    impl NoteEvents for TestEvents {
+        fn next_event(&mut self) -> Option<PluginNoteEvent<Toby>> {
+            self.incoming.pop_front()
+        }
+
+        fn send_event(&mut self, event: PluginNoteEvent<Toby>) {
+            self.sent.push(event);
+        }
+    }
+
+    /// The output of a stereo block, and the events sent back to the host during it.
+    struct Rendered {
+        left: Vec<f32>,
+        right: Vec<f32>,
+        sent: Vec<PluginNoteEvent<Toby>>,
+        status: ProcessStatus,
+    }
+
+    /// An initialized and reset Toby with smoothing bypassed, so every change lands on the very
+    /// next sample.
+    fn test_toby(params: TobyParams) -> Toby {
+        let mut toby = Toby {
+            params: Arc::new(params),
+            ..Toby::default()
+        };
+        toby.set_buffer_config(&BufferConfig {
+            sample_rate: SAMPLE_RATE,
+            min_buffer_size: None,
+            max_buffer_size: 512,
+            process_mode: ProcessMode::Realtime,
+        });
+        toby.set_smoothing_bypassed(true);
+        toby.reset();
+
+        toby
+    }
+
+    /// A parameter for overriding one of the defaults. Its range is wide enough for any value.
+    fn float_param(value: f32) -> FloatParam {
+        FloatParam::new(
+            "Test",
+            value,
+            FloatRange::Linear {
+                min: -1_000.0,
+                max: 100_000.0,
+            },
+        )
+    }
+
+    fn note_on(timing: u32, note: u8, velocity: f32) -> PluginNoteEvent<Toby> {
+        NoteEvent::NoteOn {
+            timing,
+            voice_id: None,
+            channel: 0,
+            note,
+            velocity,
+        }
+    }
+
+    fn voice_note_on(timing: u32, note: u8, voice_id: i32) -> PluginNoteEvent<Toby> {
+        NoteEvent::NoteOn {
+            timing,
+            voice_id: Some(voice_id),
+            channel: 0,
+            note,
+            velocity: 1.0,
+        }
+    }
+
+    fn cutoff_poly_mod(
+        timing: u32,
+        voice_id: i32,
+        normalized_offset: f32,
+    ) -> PluginNoteEvent<Toby> {
+        NoteEvent::PolyModulation {
+            timing,
+            voice_id,
+            poly_modulation_id: CUTOFF_POLY_MOD_ID,
+            normalized_offset,
+        }
+    }
+
+    fn note_off(timing: u32, note: u8) -> PluginNoteEvent<Toby> {
+        NoteEvent::NoteOff {
+            timing,
+            voice_id: None,
+            channel: 0,
+            note,
+            velocity: 0.0,
+        }
+    }
+
+    fn render(toby: &mut Toby, events: Vec<PluginNoteEvent<Toby>>, num_samples: usize) -> Rendered {
+        let mut events = TestEvents {
+            incoming: events.into(),
+            ..TestEvents::default()
+        };
+        let mut left = vec![0.0; num_samples];
+        let mut right = vec![0.0; num_samples];
+        let status = toby.process_block(&mut [&mut left, &mut right], None, &mut events);
+
+        Rendered {
+            left,
+            right,
+            sent: events.sent,
+            status,
+        }
+    }
+
+    #[test]
+    fn bypassed_smoothing_applies_param_changes_on_the_next_sample() {
+        let mut changed = test_toby(TobyParams::default());
+        let mut unchanged = test_toby(TobyParams::default());
+        render(&mut changed, vec![note_on(0, 60, 1.0)], 1000);
+        render(&mut unchanged, vec![note_on(0, 60, 1.0)], 1000);
+
+        changed.params = Arc::new(TobyParams {
+            gain: float_param(-20.0),
+            ..TobyParams::default()
+        });
+        let changed = render(&mut changed, Vec::new(), 1);
+        let unchanged = render(&mut unchanged, Vec::new(), 1);
+
+        let expected = util::db_to_gain(-20.0) / util::db_to_gain(-10.0);
+        assert!((changed.left[0] / unchanged.left[0] - expected).abs() < 1e-4);
+    }
+
+    #[test]
+    fn bypassed_smoothing_applies_velocity_changes_on_the_next_sample() {
+        let params = || TobyParams {
+            velocity_sensitivity: float_param(1.0),
+            filter_routing: EnumParam::new("Test", FilterRouting::PreEnvelope),
+            ..TobyParams::default()
+        };
+        let mut softer = test_toby(params());
+        let mut louder = test_toby(params());
+        render(&mut softer, vec![note_on(0, 60, 1.0)], 1000);
+        render(&mut louder, vec![note_on(0, 60, 1.0)], 1000);
+
+        let softer = render(&mut softer, vec![note_on(0, 60, 0.5)], 1);
+        let louder = render(&mut louder, vec![note_on(0, 60, 1.0)], 1);
+
+        assert!((softer.left[0] / louder.left[0] - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn overlapping_velocity_changes_ramp_in_when_smoothing() {
+        let params = || TobyParams {
+            velocity_sensitivity: float_param(1.0),
+            filter_routing: EnumParam::new("Test", FilterRouting::PreEnvelope),
+            ..TobyParams::default()
+        };
+        let mut alternating = test_toby(params());
+        let mut constant = test_toby(params());
+        render(&mut alternating, vec![note_on(0, 60, 1.0)], 2_000);
+        render(&mut constant, vec![note_on(0, 60, 1.0)], 2_000);
+        alternating.set_smoothing_bypassed(false);
+        constant.set_smoothing_bypassed(false);
+
+        // Both play the same notes, so the ratio between them is the velocity gain
+        let mut ratios = Vec::new();
+        for (note, velocity) in [(62, 0.5), (60, 1.0), (62, 0.5)] {
+            let a = render(&mut alternating, vec![note_on(0, note, velocity)], 1_000);
+            let b = render(&mut constant, vec![note_on(0, note, 1.0)], 1_000);
+            ratios.extend(a.left.iter().zip(&b.left).map(|(a, b)| {
+                if b.abs() > 1e-3 {
+                    a / b
+                } else {
+                    f32::NAN
+                }
+            }));
+        }
+
+        let ramp_samples = VELOCITY_SMOOTHING_MS / 1000.0 * SAMPLE_RATE;
+        let max_step = 0.5 / ramp_samples * 1.01;
+        // Samples skipped at zero crossings leave a gap the ratio can move further across
+        let (mut previous_index, mut previous) = (0, 1.0);
+        for (index, &ratio) in ratios
+            .iter()
+            .enumerate()
+            .filter(|(_, ratio)| !ratio.is_nan())
+        {
+            let gap = (index - previous_index).max(1) as f32;
+            assert!((ratio - previous).abs() <= max_step * gap + 1e-4);
+            (previous_index, previous) = (index, ratio);
+        }
+        // Each ramp still gets all the way to the new velocity
+        assert!((ratios[999] - 0.5).abs() < 1e-3);
+        assert!((ratios[1_999] - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn trill_within_one_block_lands_on_the_last_held_note() {
+        let mut toby = test_toby(TobyParams::default());
+        render(
+            &mut toby,
+            vec![
+                note_on(0, 60, 1.0),
+                note_on(10, 62, 1.0),
+                note_off(20, 60),
+                note_on(30, 60, 1.0),
+                note_off(40, 62),
+                note_on(50, 62, 1.0),
+                note_off(60, 62),
+            ],
+            100,
+        );
+
+        assert_eq!(toby.midi_note_id, 60);
+        assert_eq!(toby.midi_note_freq, util::midi_note_to_freq(60));
+        assert_eq!(toby.held_notes.last(), Some(60));
+        assert!(!matches!(toby.envelope.stage, EnvelopeStage::Release));
+    }
+
+    #[test]
+    fn poly_modulation_only_affects_the_targeted_voice() {
+        let mut toby = test_toby(TobyParams::default());
+        let modulated_cutoff = toby.params.cutoff.preview_modulated(0.25);
+
+        // The first voice was taken over by the second one, so modulating it does nothing
+        let rendered = render(
+            &mut toby,
+            vec![
+                voice_note_on(0, 60, 1),
+                voice_note_on(10, 62, 2),
+                cutoff_poly_mod(20, 1, 0.25),
+            ],
+            100,
+        );
+        assert!(toby.cutoff_poly_mod.is_none());
+        assert_eq!(
+            rendered.sent,
+            vec![NoteEvent::VoiceTerminated {
+                timing: 10,
+                voice_id: Some(1),
+                channel: 0,
+                note: 60,
+            }]
+        );
+
+        render(&mut toby, vec![cutoff_poly_mod(0, 2, 0.25)], 100);
+        assert_eq!(toby.cutoff_poly_mod, Some(0.25));
+        assert_eq!(toby.cutoff_poly_mod_smoother.next(), modulated_cutoff);
+
+        // Falling back to the first note ends the second voice along with its modulation, and
+        // the first voice stays gone
+        let rendered = render(
+            &mut toby,
+            vec![
+                note_off(0, 62),
+                cutoff_poly_mod(10, 1, 0.25),
+                cutoff_poly_mod(20, 2, 0.25),
+            ],
+            100,
+        );
+        assert_eq!(toby.midi_note_id, 60);
+        assert_eq!(toby.midi_note_voice_id, None);
+        assert!(toby.cutoff_poly_mod.is_none());
+        assert_eq!(
+            rendered.sent,
+            vec![NoteEvent::VoiceTerminated {
+                timing: 0,
+                voice_id: Some(2),
+                channel: 0,
+                note: 62,
+            }]
+        );
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn filter_makeup_evens_out_the_low_pass_level() {
+        let level = |mode: FilterMode, cutoff: f32, makeup: f32| {
+            let mut toby = test_toby(TobyParams {
+                filter_mode: EnumParam::new("Test", mode),
+                cutoff: float_param(cutoff),
+                filter_makeup: float_param(makeup),
+                ..TobyParams::default()
+            });
+            let rendered = render(&mut toby, vec![note_on(0, 45, 1.0)], 48_000);
+            rms(&rendered.left[24_000..])
+        };
+
+        let drop_db = |makeup| {
+            util::gain_to_db(level(FilterMode::LowPass, 100.0, makeup))
+                - util::gain_to_db(level(FilterMode::LowPass, 10_000.0, makeup))
+        };
+        let (uncompensated, compensated) = (drop_db(0.0), drop_db(1.0));
+        assert!(compensated.abs() < uncompensated.abs() / 2.0);
+
+        for mode in [FilterMode::BandPass, FilterMode::HighPass] {
+            assert_eq!(level(mode, 500.0, 0.0), level(mode, 500.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn tight_note_off_and_on_restarts_the_attack_without_releasing() {
+        let mut toby = test_toby(TobyParams::default());
+        render(&mut toby, vec![note_on(0, 60, 1.0)], 10_000);
+        let sustain = toby.envelope.current_level;
+        assert!(matches!(toby.envelope.stage, EnvelopeStage::Sustain));
+
+        // The note is played again four samples after being let go of, and the envelope never
+        // drops below where it was
+        render(&mut toby, vec![note_off(0, 60)], 1);
+        for _ in 0..3 {
+            render(&mut toby, Vec::new(), 1);
+            assert_eq!(toby.envelope.current_level, sustain);
+        }
+        render(&mut toby, vec![note_on(0, 60, 1.0)], 1);
+        assert!(matches!(toby.envelope.stage, EnvelopeStage::Attack));
+        assert!(toby.envelope.current_level > sustain);
+
+        let mut previous = toby.envelope.current_level;
+        while matches!(toby.envelope.stage, EnvelopeStage::Attack) {
+            render(&mut toby, Vec::new(), 1);
+            assert!(toby.envelope.current_level >= previous);
+            previous = toby.envelope.current_level;
+        }
+    }
+
+    #[test]
+    fn note_off_releases_after_the_debounce_window() {
+        let mut toby = test_toby(TobyParams::default());
+        render(&mut toby, vec![note_on(0, 60, 1.0)], 10_000);
+        render(
+            &mut toby,
+            vec![note_off(0, 60)],
+            RETRIGGER_DEBOUNCE_SAMPLES as usize,
+        );
+        assert!(matches!(toby.envelope.stage, EnvelopeStage::Sustain));
+        render(&mut toby, Vec::new(), 1);
+        assert!(matches!(toby.envelope.stage, EnvelopeStage::Release));
+
+        // Playing it again now is just a new note after the release
+        render(&mut toby, vec![note_on(0, 60, 1.0)], 1);
+        assert!(matches!(toby.envelope.stage, EnvelopeStage::Attack));
+    }
+
+    #[test]
+    fn retriggering_to_the_decay_skips_the_attack() {
+        let mut toby = test_toby(TobyParams {
+            env_retrigger: EnumParam::new("Test", RetriggerStage::Decay),
+            env_attack: float_param(10.0),
+            env_decay: float_param(500.0),
+            env_sustain: float_param(0.2),
+            env_release: float_param(1000.0),
+            ..TobyParams::default()
+        });
+        render(&mut toby, vec![note_on(0, 60, 1.0)], 960);
+        render(&mut toby, vec![note_off(0, 60)], 4_800);
+        assert!(matches!(toby.envelope.stage, EnvelopeStage::Release));
+
+        // The release was cut short above the sustain level, so the decay takes it down from there
+        let mut previous = toby.envelope.current_level;
+        render(&mut toby, vec![note_on(0, 62, 1.0)], 1);
+        for _ in 0..4_800 {
+            assert!(matches!(toby.envelope.stage, EnvelopeStage::Decay));
+            assert!(toby.envelope.current_level <= previous);
+            previous = toby.envelope.current_level;
+            render(&mut toby, Vec::new(), 1);
+        }
+    }
+
+    #[test]
+    fn notes_held_longer_release_longer_with_hold_scaled_release() {
+        // The time from the note off until the envelope has finished, in seconds
+        let release_time = |held_samples: usize| {
+            let mut toby = test_toby(TobyParams {
+                hold_scaled_release: BoolParam::new("Test", true),
+                env_release: float_param(1000.0),
+                ..TobyParams::default()
+            });
+            render(&mut toby, vec![note_on(0, 60, 1.0)], held_samples);
+            render(&mut toby, vec![note_off(0, 60)], 1);
+            let mut released_samples = 1;
+            while !toby.envelope.is_finished() {
+                render(&mut toby, Vec::new(), 1);
+                released_samples += 1;
+            }
+
+            released_samples as f32 / SAMPLE_RATE
+        };
+
+        let (staccato, held) = (release_time(4_800), release_time(48_000));
+        assert!((held - 1.0).abs() < 0.01);
+        assert!((staccato - MIN_HOLD_RELEASE_SCALE).abs() < 0.01);
+    }
+
+    #[test]
+    fn latch_holds_a_released_note_until_the_next_one() {
+        let mut toby = test_toby(TobyParams {
+            latch: BoolParam::new("Test", true),
+            ..TobyParams::default()
+        });
+        render(&mut toby, vec![note_on(0, 60, 1.0)], 10_000);
+        let latched = render(&mut toby, vec![note_off(0, 60)], 10_000);
+        assert!(matches!(toby.envelope.stage, EnvelopeStage::Sustain));
+        assert!(rms(&latched.left[5_000..]) > 0.01);
+
+        // The next note takes over without the latched one ever being released
+        let next = render(&mut toby, vec![note_on(0, 67, 1.0)], 10_000);
+        assert_eq!(toby.midi_note_id, 67);
+        assert!(!matches!(toby.envelope.stage, EnvelopeStage::Release));
+        assert!(next.sent.is_empty());
+        assert!((zero_crossing_frequency(&next.left[5_000..]) - 392.0).abs() < 2.0);
+    }
+
+    /// The energy at a whole number of Hertz in one second of audio.
+    fn energy_at(samples: &[f32], frequency: f32) -> f32 {
+        let (re, im) = samples
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(re, im), (n, x)| {
+                let phase = std::f32::consts::TAU * frequency * n as f32 / SAMPLE_RATE;
+                (re + x * phase.cos(), im + x * phase.sin())
+            });
+
+        (re * re + im * im) / (samples.len() * samples.len()) as f32
+    }
+
+    #[test]
+    fn pre_filter_drive_adds_harmonics_to_a_sine() {
+        let third_harmonic_db = |drive: f32| {
+            let output: Vec<f32> = (0..SAMPLE_RATE as usize)
+                .map(|n| {
+                    let sine = 0.8 * (std::f32::consts::TAU * 440.0 * n as f32 / SAMPLE_RATE).sin();
+                    Toby::pre_filter_drive(sine, drive)
+                })
+                .collect();
+
+            util::gain_to_db(energy_at(&output, 1320.0) / energy_at(&output, 440.0)) / 2.0
+        };
+
+        assert!(third_harmonic_db(0.0) < -100.0);
+        assert!(third_harmonic_db(1.0) > -20.0);
+    }
+
+    #[test]
+    fn haas_delay_delays_the_right_channel() {
+        let mut toby = test_toby(TobyParams {
+            haas_delay: float_param(1.0),
+            ..TobyParams::default()
+        });
+        let rendered = render(&mut toby, vec![note_on(0, 60, 1.0)], 4_800);
+
+        // 1 ms at 48 kHz
+        assert!(rendered.right[..48].iter().all(|&x| x == 0.0));
+        for n in 0..4_800 - 48 {
+            assert!((rendered.right[n + 48] - rendered.left[n]).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn automating_the_haas_delay_doesnt_make_the_right_channel_jump() {
+        // Filtered down to something close to a sine, so any jump in the delay would stand out
+        let mut toby = test_toby(TobyParams {
+            cutoff: float_param(400.0),
+            ..TobyParams::default()
+        });
+        let before = render(&mut toby, vec![note_on(0, 48, 1.0)], 4_800);
+        toby.set_smoothing_bypassed(false);
+        toby.params
+            .haas_delay
+            .smoothed
+            .set_target(SAMPLE_RATE, widener::MAX_DELAY_MS);
+        let mut rendered = render(&mut toby, Vec::new(), 4_800);
+        rendered.left.insert(0, before.left[4_799]);
+        rendered.right.insert(0, before.right[4_799]);
+
+        let max_step = |samples: &[f32]| {
+            samples
+                .windows(2)
+                .map(|pair| (pair[1] - pair[0]).abs())
+                .fold(0.0, f32::max)
+        };
+        assert!(max_step(&rendered.right) < 2.0 * max_step(&rendered.left));
+    }
+
+    #[test]
+    fn zero_fx_mix_is_fully_dry() {
+        let effects = |mix: f32, fx_mix: f32| TobyParams {
+            crush_bits: float_param(4.0),
+            crush_mix: float_param(mix),
+            tremolo_depth: float_param(mix),
+            haas_delay: float_param(10.0 * mix),
+            fx_mix: float_param(fx_mix),
+            ..TobyParams::default()
+        };
+        let mut wet = test_toby(effects(1.0, 1.0));
+        let mut bypassed = test_toby(effects(1.0, 0.0));
+        let mut dry = test_toby(effects(0.0, 1.0));
+        let wet = render(&mut wet, vec![note_on(0, 60, 1.0)], 10_000);
+        let bypassed = render(&mut bypassed, vec![note_on(0, 60, 1.0)], 10_000);
+        let dry = render(&mut dry, vec![note_on(0, 60, 1.0)], 10_000);
+
+        assert_ne!(wet.left, dry.left);
+        assert_eq!(bypassed.left, dry.left);
+        assert_eq!(bypassed.right, dry.right);
+    }
+
+    /// The average frequency of `samples` in Hertz, from its rising zero crossings.
+    fn zero_crossing_frequency(samples: &[f32]) -> f32 {
+        let crossings: Vec<f32> = samples
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+            .map(|(n, pair)| n as f32 + pair[0] / (pair[0] - pair[1]))
+            .collect();
+        let periods = (crossings.len() - 1) as f32;
+
+        periods * SAMPLE_RATE / (crossings[crossings.len() - 1] - crossings[0])
+    }
+
+    #[test]
+    fn keyboard_tracking_self_oscillates_at_the_note() {
+        for note in [45, 57, 69, 81] {
+            let mut toby = test_toby(TobyParams {
+                filter_tracks_keyboard: BoolParam::new("Test", true),
+                resonance: float_param(MAX_RESONANCE),
+                filter_routing: EnumParam::new("Test", FilterRouting::PostEnvelope),
+                keytrack: float_param(1.0),
+                env_to_cutoff: float_param(2.0),
+                ..TobyParams::default()
+            });
+            render(&mut toby, vec![note_on(0, note, 1.0)], 12_000);
+
+            // Once the note has been released the filter keeps ringing on its own
+            render(&mut toby, vec![note_off(0, note)], 48_000);
+            assert!(toby.envelope.is_finished());
+            let rendered = render(&mut toby, Vec::new(), 24_000);
+            assert!(rms(&rendered.left) > 0.01);
+
+            let frequency = zero_crossing_frequency(&rendered.left);
+            let cents = 1200.0 * (frequency / util::midi_note_to_freq(note)).log2();
+            assert!(
+                cents.abs() < 5.0,
+                "Note {note} self-oscillated {cents} cents off"
+            );
+        }
+    }
+
+    #[test]
+    fn new_buffer_size_keeps_the_held_note() {
+        let mut reconfigured = test_toby(TobyParams::default());
+        let mut untouched = test_toby(TobyParams::default());
+        render(&mut reconfigured, vec![note_on(0, 64, 1.0)], 10_000);
+        render(&mut untouched, vec![note_on(0, 64, 1.0)], 10_000);
+
+        reconfigured.set_buffer_config(&BufferConfig {
+            sample_rate: SAMPLE_RATE,
+            min_buffer_size: None,
+            max_buffer_size: 2048,
+            process_mode: ProcessMode::Realtime,
+        });
+        assert!(matches!(
+            reconfigured.envelope.stage,
+            EnvelopeStage::Sustain
+        ));
+        assert_eq!(reconfigured.midi_note_freq, util::midi_note_to_freq(64));
+
+        let reconfigured = render(&mut reconfigured, Vec::new(), 2048);
+        let untouched = render(&mut untouched, Vec::new(), 2048);
+        assert_eq!(reconfigured.left, untouched.left);
+        assert_eq!(reconfigured.right, untouched.right);
+    }
+
+    #[test]
+    fn idle_blocks_skip_the_voice_loop() {
+        // The held sample counter only advances inside the per-sample loop
+        let mut toby = test_toby(TobyParams::default());
+        let rendered = render(&mut toby, Vec::new(), 512);
+        assert!(matches!(rendered.status, ProcessStatus::Normal));
+        assert!(rendered
+            .left
+            .iter()
+            .chain(&rendered.right)
+            .all(|&x| x == 0.0));
+        assert_eq!(toby.note_held_samples, 0);
+
+        // A note in the middle of a block wakes it back up from that sample on
+        let rendered = render(&mut toby, vec![note_on(256, 60, 1.0)], 512);
+        assert!(matches!(rendered.status, ProcessStatus::KeepAlive));
+        assert!(rendered.left[..256].iter().all(|&x| x == 0.0));
+        assert!(rendered.left[256..].iter().any(|&x| x != 0.0));
+    }
+
+    #[test]
+    fn more_analog_slop_varies_repeated_notes_more() {
+        // The spread in cents of the pitch of the same note played eight times in a row
+        let pitch_spread = |slop: f32| {
+            let mut toby = test_toby(TobyParams {
+                analog_slop: float_param(slop),
+                ..TobyParams::default()
+            });
+            let cents: Vec<f32> = (0..8)
+                .map(|_| {
+                    let rendered = render(&mut toby, vec![note_on(0, 57, 1.0)], 9_600);
+                    render(&mut toby, vec![note_off(0, 57)], 24_000);
+                    assert!(toby.envelope.is_finished());
+
+                    let frequency = zero_crossing_frequency(&rendered.left[4_800..]);
+                    1200.0 * (frequency / 220.0).log2()
+                })
+                .collect();
+            let mean = cents.iter().sum::<f32>() / cents.len() as f32;
+
+            (cents.iter().map(|c| (c - mean).powi(2)).sum::<f32>() / cents.len() as f32).sqrt()
+        };
+
+        let (none, some, full) = (pitch_spread(0.0), pitch_spread(0.5), pitch_spread(1.0));
+        assert!(none < 0.1);
+        assert!(some > none + 1.0);
+        assert!(full > some);
+    }
+
+    #[test]
+    fn full_left_balance_silences_the_right_channel() {
+        let mut centered = test_toby(TobyParams::default());
+        let mut left = test_toby(TobyParams {
+            balance: float_param(-1.0),
+            ..TobyParams::default()
+        });
+        let centered = render(&mut centered, vec![note_on(0, 60, 1.0)], 4_800);
+        let left = render(&mut left, vec![note_on(0, 60, 1.0)], 4_800);
+
+        assert!(left.right.iter().all(|&x| x == 0.0));
+        assert_eq!(left.left, centered.left);
+    }
+
+    #[test]
+    fn key_pan_spreads_notes_across_the_stereo_field() {
+        let render_note = |key_pan: f32, note: u8| {
+            let mut toby = test_toby(TobyParams {
+                key_pan: float_param(key_pan),
+                haas_delay: float_param(0.0),
+                ..TobyParams::default()
+            });
+            render(&mut toby, vec![note_on(0, note, 1.0)], 4_800)
+        };
+
+        for note in [36, 84] {
+            let centered = render_note(0.0, note);
+            let panned = render_note(1.0, note);
+            assert_eq!(centered.left, centered.right);
+
+            // The side the note is panned towards stays at unity gain, so panning never clips
+            let (near, far) = if note > 60 {
+                (&panned.right, &panned.left)
+            } else {
+                (&panned.left, &panned.right)
+            };
+            assert_eq!(near, &centered.left);
+            assert!(rms(far) < 0.5 * rms(near));
+        }
+    }
+
+    #[test]
+    fn the_same_seed_renders_the_same_randomized_output() {
+        let seeded_render = |seed: u32| {
+            let mut toby = test_toby(TobyParams {
+                noise_level: float_param(0.5),
+                analog_slop: float_param(1.0),
+                resonance: float_param(MAX_RESONANCE),
+                ..TobyParams::default()
+            });
+            toby.set_rng_seed(seed);
+            toby.reset();
+
+            let mut left = Vec::new();
+            for note in [57, 60, 64] {
+                left.extend(render(&mut toby, vec![note_on(0, note, 1.0)], 4_800).left);
+                left.extend(render(&mut toby, vec![note_off(0, note)], 4_800).left);
+            }
+
+            left
+        };
+
+        let (first, second, other) = (seeded_render(7), seeded_render(7), seeded_render(8));
+        assert!(first
+            .iter()
+            .zip(&second)
+            .all(|(a, b)| a.to_bits() == b.to_bits()));
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn morphing_raises_the_resonance() {
+        assert_eq!(Toby::filter_resonance(2.0, 1.0, 0.0), 2.0);
+        assert_eq!(Toby::filter_resonance(2.0, 0.0, 1.0), 2.0);
+        assert_eq!(Toby::filter_resonance(2.0, 1.0, 1.0), 32.0);
+        assert_eq!(Toby::filter_resonance(50.0, 1.0, 1.0), MAX_RESONANCE);
+
+        // How far the harmonic at the cutoff stands out above the fundamental
+        let peak_db = |morph_to_resonance: f32| {
+            let mut toby = test_toby(TobyParams {
+                morph: float_param(1.0),
+                morph_to_resonance: float_param(morph_to_resonance),
+                cutoff: float_param(util::midi_note_to_freq(84)),
+                resonance: float_param(2.0),
+                ..TobyParams::default()
+            });
+            let left = render(&mut toby, vec![note_on(0, 48, 1.0)], SAMPLE_RATE as usize).left;
+
+            util::gain_to_db(
+                energy_at(&left, util::midi_note_to_freq(84))
+                    / energy_at(&left, util::midi_note_to_freq(48)),
+            ) / 2.0
+        };
+
+        assert!(peak_db(1.0) > peak_db(0.0) + 12.0);
+    }
+
+    #[test]
+    fn only_the_pre_envelope_filter_fades_in_under_a_slow_attack() {
+        let render_routing = |filter_routing: FilterRouting| {
+            let mut toby = test_toby(TobyParams {
+                filter_routing: EnumParam::new("Test", filter_routing),
+                env_attack: float_param(500.0),
+                resonance: float_param(MAX_RESONANCE),
+                ..TobyParams::default()
+            });
+
+            render(&mut toby, vec![note_on(0, 48, 1.0)], 4_800).left
+        };
+        let pre = render_routing(FilterRouting::PreEnvelope);
+        let post = render_routing(FilterRouting::PostEnvelope);
+
+        // A self-oscillating filter rings at its own level no matter how quiet its input is, so
+        // after the envelope it ignores the attack, while before the envelope it gets faded in
+        // along with everything else
+        assert!(rms(&post) > 1.5 * rms(&pre));
+    }
+
+    #[test]
+    fn tremolo_follows_its_rate() {
+        for rate in [3.0, 5.0, 8.0] {
+            let mut toby = test_toby(TobyParams {
+                tremolo_depth: float_param(1.0),
+                tremolo_rate: float_param(rate),
+                ..TobyParams::default()
+            });
+            let left = render(&mut toby, vec![note_on(0, 60, 1.0)], SAMPLE_RATE as usize).left;
+            let level: Vec<f32> = left.iter().map(|x| x.abs()).collect();
+
+            // The level swells and dips at the rate, and not at the neighboring frequencies
+            let at_rate = energy_at(&level, rate);
+            assert!(at_rate > 100.0 * energy_at(&level, rate - 1.0));
+            assert!(at_rate > 100.0 * energy_at(&level, rate + 1.0));
+        }
+    }
+
+    /// The pitch of each period of `samples` in semitones away from `note`, from the spacing of
+    /// its rising zero crossings.
+    fn semitones_per_period(samples: &[f32], note: u8) -> Vec<f32> {
+        let crossings: Vec<f32> = samples
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+            .map(|(n, pair)| n as f32 + pair[0] / (pair[0] - pair[1]))
+            .collect();
+
+        crossings
+            .windows(2)
+            .map(|pair| {
+                let frequency = SAMPLE_RATE / (pair[1] - pair[0]);
+                12.0 * (frequency / util::midi_note_to_freq(note)).log2()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn vibrato_bends_the_pitch_by_its_depth_at_its_rate() {
+        // Filtered down to close to a sine, so the zero crossings follow the fundamental
+        let mut toby = test_toby(TobyParams {
+            vibrato_depth: float_param(1.0),
+            vibrato_rate: float_param(5.0),
+            cutoff: float_param(150.0),
+            filter_slope: EnumParam::new("Test", FilterSlope::Db24),
+            ..TobyParams::default()
+        });
+        let left = render(&mut toby, vec![note_on(0, 48, 1.0)], SAMPLE_RATE as usize).left;
+        let semitones = semitones_per_period(&left[4_800..], 48);
+
+        let highest = semitones.iter().copied().fold(f32::MIN, f32::max);
+        let lowest = semitones.iter().copied().fold(f32::MAX, f32::min);
+        let upswings = semitones
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        assert!((highest - 1.0).abs() < 0.05);
+        assert!((lowest + 1.0).abs() < 0.05);
+        // 0.9 seconds at 5 Hz
+        assert!((4..=5).contains(&upswings));
+    }
+
+    #[test]
+    fn pitch_envelope_starts_offset_and_settles_on_the_note() {
+        for amount in [-12.0, 12.0] {
+            let mut toby = test_toby(TobyParams {
+                pitch_env_amount: float_param(amount),
+                pitch_env_decay: float_param(300.0),
+                cutoff: float_param(300.0),
+                filter_slope: EnumParam::new("Test", FilterSlope::Db24),
+                ..TobyParams::default()
+            });
+            let left = render(&mut toby, vec![note_on(0, 48, 1.0)], SAMPLE_RATE as usize).left;
+            // The first period is skewed by the filter starting up
+            let semitones = &semitones_per_period(&left, 48)[1..];
+
+            assert!((semitones[0] - amount).abs() < 1.5);
+            assert!(semitones[semitones.len() - 1].abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn editor_state_survives_a_save_and_restore() {
+        let saved = TobyParams::default();
+        *saved.editor_state.write().unwrap() = EditorState {
+            scroll: 120.0,
+            zoom: 1.5,
+        };
+
+        let restored = TobyParams::default();
+        restored.deserialize_fields(&saved.serialize_fields());
+        let editor_state = restored.editor_state.read().unwrap();
+        assert_eq!(editor_state.scroll, 120.0);
+        assert_eq!(editor_state.zoom, 1.5);
+    }
+
+    #[test]
+    fn automating_the_release_mid_tail_doesnt_make_the_level_jump() {
+        // Shortening the release from 100 ms to 10 ms 40 ms into it would otherwise put the
+        // release well past its end, and cut the note off on the spot
+        let mut toby = test_toby(TobyParams::default());
+        render(&mut toby, vec![note_on(0, 60, 1.0)], 4_800);
+        render(&mut toby, vec![note_off(0, 60)], 1_920);
+        assert!(matches!(toby.envelope.stage, EnvelopeStage::Release));
+
+        toby.set_smoothing_bypassed(false);
+        toby.params
+            .env_release
+            .smoothed
+            .set_target(SAMPLE_RATE, 10.0);
+        let mut previous = toby.envelope.current_level;
+        let mut largest_step: f32 = 0.0;
+        while !toby.envelope.is_finished() {
+            render(&mut toby, Vec::new(), 1);
+            largest_step = largest_step.max(previous - toby.envelope.current_level);
+            previous = toby.envelope.current_level;
+        }
+
+        assert!(
+            largest_step < 0.01,
+            "The level dropped by {largest_step} in one sample"
+        );
+    }
+
+    #[test]
+    fn negative_env_to_cutoff_darkens_the_attack() {
+        // How loud the fourth to eighth harmonics are next to the fundamental, in decibels
+        let fundamental = util::midi_note_to_freq(48);
+        let brightness = |samples: &[f32]| {
+            let harmonics: f32 = (4..=8)
+                .map(|harmonic| energy_at(samples, harmonic as f32 * fundamental))
+                .sum();
+
+            util::gain_to_db(harmonics / energy_at(samples, fundamental)) / 2.0
+        };
+        let render_amount = |env_to_cutoff: f32| {
+            let mut toby = test_toby(TobyParams {
+                morph: float_param(0.75),
+                cutoff: float_param(2_000.0),
+                env_to_cutoff: float_param(env_to_cutoff),
+                ..TobyParams::default()
+            });
+
+            render(&mut toby, vec![note_on(0, 48, 1.0)], 24_000).left
+        };
+        let unmodulated = render_amount(0.0);
+        let darkened = render_amount(-3.0);
+
+        // The filter envelope peaks 10 ms in, and has decayed back to nothing 310 ms in
+        let (peak, settled) = (480..1_920, 16_800..24_000);
+        assert!(brightness(&darkened[peak.clone()]) < brightness(&unmodulated[peak]) - 10.0);
+        assert!(
+            (brightness(&darkened[settled.clone()]) - brightness(&unmodulated[settled])).abs()
+                < 0.1
+        );
+    }
+}
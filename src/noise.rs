@@ -0,0 +1,54 @@
+use crate::util::Rng;
+use nih_plug::prelude::Enum;
+
+/// Brings the pink noise down to the same RMS level as the white noise.
+const PINK_GAIN: f32 = 1.0 / 3.0;
+
+#[derive(Enum, Clone, Copy, PartialEq)]
+pub enum NoiseColor {
+    /// Equal energy at every frequency. Bright and hissy.
+    White,
+    /// Rolls off at 3 dB per octave, so every octave has the same energy. Softer, like wind or
+    /// rain.
+    Pink,
+}
+
+/// A noise source for mixing into the voice.
+pub struct Noise {
+    rng: Rng,
+    /// The states of the three one-pole low passes that are summed to tilt white noise into pink
+    /// noise.
+    pink_states: [f32; 3],
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self {
+            rng: Rng::new(1),
+            pink_states: [0.0; 3],
+        }
+    }
+}
+
+impl Noise {
+    /// Restart the noise from `seed` and clear the pink filter.
+    pub fn reset(&mut self, seed: u32) {
+        self.rng = Rng::new(seed);
+        self.pink_states = [0.0; 3];
+    }
+
+    pub fn next(&mut self, color: NoiseColor) -> f32 {
+        let white = self.rng.next_bipolar();
+
+        // Paul Kellet's economy pink noise filter. The pink filter is always run so switching
+        // colors doesn't start it from silence.
+        self.pink_states[0] = 0.99765 * self.pink_states[0] + white * 0.099_046;
+        self.pink_states[1] = 0.96300 * self.pink_states[1] + white * 0.296_516_4;
+        self.pink_states[2] = 0.57000 * self.pink_states[2] + white * 1.052_691_3;
+
+        match color {
+            NoiseColor::White => white,
+            NoiseColor::Pink => (self.pink_states.iter().sum::<f32>() + white * 0.1848) * PINK_GAIN,
+        }
+    }
+}
@@ -0,0 +1,52 @@
+/// The maximum number of notes that can be held down at once. Anything pressed beyond this pushes
+/// out the oldest held note.
+const MAX_HELD_NOTES: usize = 16;
+
+/// The notes currently held down, in the order they were pressed. Since Toby is monophonic, the
+/// most recently pressed note that's still held is the one that sounds (last note priority).
+pub struct NoteStack {
+    notes: [u8; MAX_HELD_NOTES],
+    len: usize,
+}
+
+impl Default for NoteStack {
+    fn default() -> Self {
+        Self {
+            notes: [0; MAX_HELD_NOTES],
+            len: 0,
+        }
+    }
+}
+
+impl NoteStack {
+    /// Push a newly pressed note. If the note was already held it's moved to the top instead of
+    /// being added twice.
+    pub fn push(&mut self, note: u8) {
+        self.remove(note);
+
+        if self.len == MAX_HELD_NOTES {
+            self.notes.copy_within(1.., 0);
+            self.len -= 1;
+        }
+
+        self.notes[self.len] = note;
+        self.len += 1;
+    }
+
+    /// Remove a released note, wherever it is in the stack.
+    pub fn remove(&mut self, note: u8) {
+        if let Some(index) = self.notes[..self.len].iter().position(|&n| n == note) {
+            self.notes.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+        }
+    }
+
+    /// The note that should currently be sounding, if any.
+    pub fn last(&self) -> Option<u8> {
+        self.notes[..self.len].last().copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
@@ -3,14 +3,58 @@ use super::{
 };
 
 const NOTCH_DEPTH: f32 = 0.2;
+/// The range of the morph control over which the waveshape crossfades from the triangle to the
+/// notched saw.
+const SHAPE_CROSSFADE_START: f32 = 0.3;
+const SHAPE_CROSSFADE_END: f32 = 0.6;
+/// The most the level compensation will boost the oscillator by. Very narrow notches are mostly
+/// DC, and making up all of their level would make the peaks huge.
+const MAX_LEVEL_COMPENSATION: f32 = 2.0;
+/// The time constant of the smoothing applied to frequency changes, so a sudden jump in pitch
+/// doesn't produce a discontinuity in the waveform's slope.
+const FREQUENCY_SMOOTHING_MS: f32 = 1.0;
+
+/// Map a single morph control in `[0, 1]` to the pulse width and waveshape passed to
+/// [`VariableSawOscillator::prepare`]. The lower half sweeps a triangle's symmetry, the upper half
+/// narrows a notched saw, and the two are crossfaded with a smoothstep so a linear sweep of the
+/// morph never makes the timbre jump.
+pub fn morph_to_pw_and_waveshape(morph: f32) -> (f32, f32) {
+    let pw = if morph < 0.5 {
+        morph + 0.5
+    } else {
+        1.0 - (morph - 0.5) * 2.0
+    };
+    let pw = (pw * 1.1).clamp(0.005, 1.0);
+
+    let t = ((morph - SHAPE_CROSSFADE_START) / (SHAPE_CROSSFADE_END - SHAPE_CROSSFADE_START))
+        .clamp(0.0, 1.0);
+    let waveshape = 1.0 - t * t * (3.0 - 2.0 * t);
+
+    (pw, waveshape)
+}
 
 pub struct VariableSawOscillator {
     // Parameters
     pw: f32,
     waveshape: f32,
+    /// Scales the BLEP corrections, from a raw aliased waveform at 0 to fully band-limited at 1.
+    antialias: f32,
+    /// Whether frequency changes are smoothed. Turned off for offline renders, where every change
+    /// should take effect on the very next sample.
+    pub smooth_frequency: bool,
+    /// The waveform's DC offset and the gain applied around it to keep its level constant across
+    /// the waveshape and pulse width range. Computed in `prepare()` whenever the pulse width or
+    /// waveshape changes.
+    level_dc: f32,
+    level_gain: f32,
+    level_pw: f32,
+    level_waveshape: f32,
 
     // State
     phase: f32,
+    /// The smoothed phase increment. Zero until the first call to `prepare()`, which starts it at
+    /// the right frequency instead of gliding up to it.
+    phase_delta: f32,
     high: bool,
     next_sample: f32,
     previous_pw: f32,
@@ -20,19 +64,41 @@ impl Default for VariableSawOscillator {
     fn default() -> Self {
         Self {
             phase: 0.0,
+            phase_delta: 0.0,
             next_sample: 0.0,
             previous_pw: 0.5,
             high: false,
 
             pw: 0.5,
             waveshape: 0.0,
+            antialias: 1.0,
+            smooth_frequency: true,
+            level_dc: 0.0,
+            level_gain: 1.0,
+            level_pw: f32::NAN,
+            level_waveshape: f32::NAN,
         }
     }
 }
 
 impl VariableSawOscillator {
-    pub fn prepare(&mut self, pw: f32, waveshape: f32, frequency: f32, sample_rate: f32) {
-        let phase_delta = frequency / sample_rate;
+    pub fn prepare(
+        &mut self,
+        pw: f32,
+        waveshape: f32,
+        antialias: f32,
+        frequency: f32,
+        sample_rate: f32,
+    ) {
+        let target_phase_delta = super::phase_delta(frequency, sample_rate);
+        if self.phase_delta == 0.0 || !self.smooth_frequency {
+            self.phase_delta = target_phase_delta;
+        } else {
+            let coefficient = 1.0 - (-1000.0 / (FREQUENCY_SMOOTHING_MS * sample_rate)).exp();
+            self.phase_delta += (target_phase_delta - self.phase_delta) * coefficient;
+        }
+
+        let phase_delta = self.phase_delta;
         let pw = if phase_delta >= 0.25 {
             0.5
         } else {
@@ -41,13 +107,27 @@ impl VariableSawOscillator {
 
         self.pw = pw;
         self.waveshape = waveshape;
+        self.antialias = antialias;
+        if pw != self.level_pw || waveshape != self.level_waveshape {
+            self.level_pw = pw;
+            self.level_waveshape = waveshape;
+            (self.level_dc, self.level_gain) = level_compensation(pw, waveshape);
+        }
     }
 
-    pub fn process(&mut self, frequency: f32, sample_rate: f32) -> f32 {
+    /// Jump ahead by `offset` cycles. This makes the waveform jump too, so it's only meant for
+    /// notes that start from silence.
+    pub fn offset_phase(&mut self, offset: f32) {
+        self.phase = (self.phase + offset).fract();
+        self.high = self.phase >= self.pw;
+    }
+
+    /// Render the next sample at the frequency, pulse width and waveshape set by `prepare()`.
+    pub fn process(&mut self) -> f32 {
         let mut this_sample = self.next_sample;
         self.next_sample = 0.0;
 
-        let phase_delta = frequency / sample_rate;
+        let phase_delta = self.phase_delta;
         let triangle_amount = self.waveshape;
         let notch_amount = 1.0 - self.waveshape;
 
@@ -57,8 +137,9 @@ impl VariableSawOscillator {
         self.phase += phase_delta;
 
         if !self.high && self.phase >= self.pw {
-            let triangle_step = (slope_up + slope_down) * phase_delta * triangle_amount;
-            let notch = (NOTCH_DEPTH + 1.0 - self.pw) * notch_amount;
+            let triangle_step =
+                (slope_up + slope_down) * phase_delta * triangle_amount * self.antialias;
+            let notch = (NOTCH_DEPTH + 1.0 - self.pw) * notch_amount * self.antialias;
 
             let t = (self.phase - self.pw) / (self.previous_pw - self.pw + phase_delta);
 
@@ -72,25 +153,29 @@ impl VariableSawOscillator {
         } else if self.phase >= 1.0 {
             self.phase -= 1.0;
 
-            let triangle_step = (slope_up + slope_down) * phase_delta * triangle_amount;
-            let notch = (NOTCH_DEPTH + 1.0) * notch_amount;
+            let triangle_step =
+                (slope_up + slope_down) * phase_delta * triangle_amount * self.antialias;
+            let notch = (NOTCH_DEPTH + 1.0) * notch_amount * self.antialias;
 
             let t = self.phase / phase_delta;
 
-            this_sample += notch * this_blep_sample(t);
-            self.next_sample += notch * next_blep_sample(t);
+            // The saw falls back down here and the triangle starts rising again, so both
+            // corrections go the other way
+            this_sample -= notch * this_blep_sample(t);
+            self.next_sample -= notch * next_blep_sample(t);
 
-            this_sample -= triangle_step * this_integrated_blep_sample(t);
-            self.next_sample -= triangle_step * next_integrated_blep_sample(t);
+            this_sample += triangle_step * this_integrated_blep_sample(t);
+            self.next_sample += triangle_step * next_integrated_blep_sample(t);
 
             self.high = false;
         }
 
-        self.next_sample =
+        self.next_sample +=
             self.compute_naive_sample(slope_up, slope_down, triangle_amount, notch_amount);
         self.previous_pw = self.pw;
 
-        return (2.0 * this_sample - 1.0) / (1.0 + NOTCH_DEPTH);
+        let output = (2.0 * this_sample - 1.0) / (1.0 + NOTCH_DEPTH);
+        return self.level_dc + (output - self.level_dc) * self.level_gain;
     }
 
     fn compute_naive_sample(
@@ -115,3 +200,211 @@ impl VariableSawOscillator {
         return notch_saw * notch_amount + triangle * triangle_amount;
     }
 }
+
+/// The DC offset of the oscillator's output for this pulse width and waveshape, and the gain that
+/// brings everything but that offset to the triangle's RMS level. The triangle's level doesn't
+/// depend on its symmetry, but the notched saw gets louder and then quieter as its notch widens.
+fn level_compensation(pw: f32, waveshape: f32) -> (f32, f32) {
+    let triangle_amount = waveshape;
+    let notch_amount = 1.0 - waveshape;
+    let notch_level = 1.0 + NOTCH_DEPTH;
+
+    // The naive waveforms' means and variances before they're scaled to the output range. The
+    // notched saw ramps from 0 to `pw` and then stays at `notch_level` for the rest of the cycle.
+    let triangle_mean = 0.5;
+    let triangle_variance: f32 = 1.0 / 12.0;
+    let notch_mean = pw * pw / 2.0 + (1.0 - pw) * notch_level;
+    let notch_variance =
+        pw * pw * pw / 3.0 + (1.0 - pw) * notch_level * notch_level - notch_mean * notch_mean;
+
+    // The two waveforms rise and fall together, so the crossfade's variance includes their
+    // covariance. The triangle ramps up alongside the saw and then falls while the saw sits at
+    // `notch_level`.
+    let product_mean = pw * pw / 3.0 + notch_level * (1.0 - pw) / 2.0;
+    let covariance = product_mean - triangle_mean * notch_mean;
+
+    let mean = triangle_amount * triangle_mean + notch_amount * notch_mean;
+    let variance = triangle_amount * triangle_amount * triangle_variance
+        + notch_amount * notch_amount * notch_variance
+        + 2.0 * triangle_amount * notch_amount * covariance;
+
+    let dc = (2.0 * mean - 1.0) / notch_level;
+    let gain = (triangle_variance / variance.max(f32::EPSILON))
+        .sqrt()
+        .min(MAX_LEVEL_COMPENSATION);
+
+    (dc, gain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+    /// The tests render a tenth of a second, so every component of a waveform at a multiple of
+    /// 10 Hz lands exactly on one of the 10 Hz DFT bins and nothing leaks between them.
+    const NUM_SAMPLES: usize = 4_800;
+    const BIN_WIDTH: f32 = 10.0;
+
+    fn render(morph: f32, antialias: f32, frequency: f32) -> Vec<f32> {
+        let (pw, waveshape) = morph_to_pw_and_waveshape(morph);
+        let mut oscillator = VariableSawOscillator::default();
+        (0..NUM_SAMPLES)
+            .map(|_| {
+                oscillator.prepare(pw, waveshape, antialias, frequency, SAMPLE_RATE);
+                oscillator.process()
+            })
+            .collect()
+    }
+
+    fn energy_at(samples: &[f32], frequency: f32) -> f32 {
+        let (re, im) = samples
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(re, im), (n, x)| {
+                let phase = consts::TAU * frequency * n as f32 / SAMPLE_RATE;
+                (re + x * phase.cos(), im + x * phase.sin())
+            });
+
+        (re * re + im * im) / (samples.len() * samples.len()) as f32
+    }
+
+    /// Everything between DC and the fundamental can only be aliasing.
+    fn aliasing_energy(samples: &[f32], frequency: f32) -> f32 {
+        (1..(frequency / BIN_WIDTH) as usize)
+            .map(|bin| energy_at(samples, bin as f32 * BIN_WIDTH))
+            .sum()
+    }
+
+    #[test]
+    fn antialiasing_reduces_aliasing() {
+        let frequency = 2_630.0;
+        for morph in [0.0, 0.5, 0.75, 1.0] {
+            let raw = aliasing_energy(&render(morph, 0.0, frequency), frequency);
+            let antialiased = aliasing_energy(&render(morph, 1.0, frequency), frequency);
+            assert!(
+                antialiased < raw / 4.0,
+                "Morph {morph} had an aliasing energy of {antialiased} with antialiasing and {raw} \
+                 without"
+            );
+        }
+    }
+
+    #[test]
+    fn morph_mapping_has_no_steps() {
+        // The steepest part of either mapping moves by about 5 per unit of morph
+        let steps = 10_000;
+        let mapped: Vec<(f32, f32)> = (0..=steps)
+            .map(|i| morph_to_pw_and_waveshape(i as f32 / steps as f32))
+            .collect();
+        for (i, pair) in mapped.windows(2).enumerate() {
+            let morph = i as f32 / steps as f32;
+            assert!(
+                (pair[1].0 - pair[0].0).abs() < 1e-3,
+                "The pulse width jumps at morph {morph}"
+            );
+            assert!(
+                (pair[1].1 - pair[0].1).abs() < 1e-3,
+                "The waveshape jumps at morph {morph}"
+            );
+        }
+    }
+
+    fn max_step(samples: &[f32]) -> f32 {
+        samples
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn sweeping_the_morph_adds_no_jumps() {
+        let frequency = 110.0;
+        let mut oscillator = VariableSawOscillator::default();
+        let swept: Vec<f32> = (0..NUM_SAMPLES)
+            .map(|n| {
+                let (pw, waveshape) = morph_to_pw_and_waveshape(n as f32 / NUM_SAMPLES as f32);
+                oscillator.prepare(pw, waveshape, 1.0, frequency, SAMPLE_RATE);
+                oscillator.process()
+            })
+            .collect();
+
+        // The waveform's own edges are the biggest steps any static morph makes
+        let largest_static_step = (0..=20)
+            .map(|i| max_step(&render(i as f32 / 20.0, 1.0, frequency)))
+            .fold(0.0, f32::max);
+        assert!(max_step(&swept) <= largest_static_step * 1.05);
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        (samples.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn level_stays_constant_across_the_morph() {
+        let reference = rms(&render(0.5, 1.0, 110.0));
+        // The narrowest notches need more boost than `MAX_LEVEL_COMPENSATION` allows
+        for morph in (0..20).map(|i| i as f32 / 20.0) {
+            let level_db = 20.0 * (rms(&render(morph, 1.0, 110.0)) / reference).log10();
+            assert!(level_db.abs() < 0.25, "Morph {morph} is {level_db} dB off");
+        }
+    }
+
+    #[test]
+    fn phase_offset_skips_ahead_in_the_cycle() {
+        let (pw, waveshape) = morph_to_pw_and_waveshape(0.75);
+        let mut skipped = VariableSawOscillator::default();
+        let mut played = VariableSawOscillator::default();
+        let next = |oscillator: &mut VariableSawOscillator| {
+            oscillator.prepare(pw, waveshape, 1.0, 480.0, SAMPLE_RATE);
+            oscillator.process()
+        };
+
+        // A quarter of a cycle at 480 Hz is 25 samples
+        next(&mut skipped);
+        skipped.offset_phase(0.25);
+        for _ in 0..26 {
+            next(&mut played);
+        }
+        // The output runs a sample behind, so the jump only shows up one sample later
+        next(&mut skipped);
+        next(&mut played);
+        for _ in 0..200 {
+            assert!((next(&mut skipped) - next(&mut played)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn frequency_jumps_are_smoothed() {
+        let (pw, waveshape) = morph_to_pw_and_waveshape(0.2);
+        let phase_deltas = |smooth_frequency: bool| {
+            let mut oscillator = VariableSawOscillator {
+                smooth_frequency,
+                ..VariableSawOscillator::default()
+            };
+            for _ in 0..100 {
+                oscillator.prepare(pw, waveshape, 1.0, 100.0, SAMPLE_RATE);
+                oscillator.process();
+            }
+
+            (0..480)
+                .map(|_| {
+                    oscillator.prepare(pw, waveshape, 1.0, 1_000.0, SAMPLE_RATE);
+                    oscillator.process();
+                    oscillator.phase_delta
+                })
+                .collect::<Vec<_>>()
+        };
+        let (from, to) = (100.0 / SAMPLE_RATE, 1_000.0 / SAMPLE_RATE);
+
+        // The increment glides up over about a millisecond instead of jumping straight there
+        let smoothed = phase_deltas(true);
+        assert!(smoothed[0] < from + (to - from) * 0.1);
+        assert!(smoothed.windows(2).all(|pair| pair[1] > pair[0]));
+        assert!((smoothed[479] - to).abs() < to * 1e-3);
+
+        assert_eq!(phase_deltas(false)[0], to);
+    }
+}
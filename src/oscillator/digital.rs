@@ -1,3 +1,4 @@
+use nih_plug::prelude::Enum;
 use std::f32::consts::{self, LN_2};
 
 pub enum Shape {
@@ -7,6 +8,25 @@ pub enum Shape {
     Sine,
 }
 
+/// How far below the main oscillator the sub oscillator plays.
+#[derive(Enum, Clone, Copy, PartialEq)]
+pub enum SubOctave {
+    #[name = "-1 Oct"]
+    OneDown,
+    #[name = "-2 Oct"]
+    TwoDown,
+}
+
+impl SubOctave {
+    /// The factor the main oscillator's frequency is multiplied by.
+    pub fn ratio(self) -> f32 {
+        match self {
+            SubOctave::OneDown => 0.5,
+            SubOctave::TwoDown => 0.25,
+        }
+    }
+}
+
 pub struct DigitalOscillator {
     phase: f32,
     shape: Shape,
@@ -26,8 +46,12 @@ impl DigitalOscillator {
         Self { phase: 0.0, shape }
     }
 
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+
     fn process_sine(&mut self, frequency: f32, sample_rate: f32) -> f32 {
-        let phase_delta = frequency / sample_rate;
+        let phase_delta = super::phase_delta(frequency, sample_rate);
 
         // Multiply by tau to make the period = 1
         let sine = (self.phase * consts::TAU).sin();
@@ -41,9 +65,9 @@ impl DigitalOscillator {
     }
 
     fn process_square(&mut self, frequency: f32, sample_rate: f32) -> f32 {
-        let phase_delta = frequency / sample_rate;
+        let phase_delta = super::phase_delta(frequency, sample_rate);
 
-        let square = if self.phase >= 0.5 { 0.0 } else { 1.0 };
+        let square = if self.phase >= 0.5 { -1.0 } else { 1.0 };
 
         self.phase += phase_delta;
         if self.phase >= 1.0 {
@@ -53,11 +77,39 @@ impl DigitalOscillator {
         square
     }
 
+    fn process_saw(&mut self, frequency: f32, sample_rate: f32) -> f32 {
+        let phase_delta = super::phase_delta(frequency, sample_rate);
+
+        let saw = 2.0 * self.phase - 1.0;
+
+        self.phase += phase_delta;
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+        }
+
+        saw
+    }
+
+    fn process_triangle(&mut self, frequency: f32, sample_rate: f32) -> f32 {
+        let phase_delta = super::phase_delta(frequency, sample_rate);
+
+        // Offset by a quarter period so it starts at zero and rises, like the sine
+        let triangle = 1.0 - 4.0 * ((self.phase + 0.25) % 1.0 - 0.5).abs();
+
+        self.phase += phase_delta;
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+        }
+
+        triangle
+    }
+
     pub fn process(&mut self, frequency: f32, sample_rate: f32) -> f32 {
         match self.shape {
             Shape::Sine => self.process_sine(frequency, sample_rate),
             Shape::Square => self.process_square(frequency, sample_rate),
-            _ => todo!(),
+            Shape::Saw => self.process_saw(frequency, sample_rate),
+            Shape::Triangle => self.process_triangle(frequency, sample_rate),
         }
     }
 }
@@ -86,3 +138,58 @@ impl BlendOscillator {
         return (a * (1.0 - self.shape)) + (b * self.shape);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    fn mean(samples: &[f32]) -> f32 {
+        samples.iter().sum::<f32>() / samples.len() as f32
+    }
+
+    #[test]
+    fn square_has_no_dc_offset() {
+        let mut osc = DigitalOscillator::new(Shape::Square);
+        // 375 Hz is exactly 128 samples per period, so the phase steps are exact
+        let period: Vec<f32> = (0..128).map(|_| osc.process(375.0, SAMPLE_RATE)).collect();
+
+        assert!(mean(&period).abs() < 1e-3);
+    }
+
+    #[test]
+    fn blend_has_no_dc_offset() {
+        let mut osc = BlendOscillator::default();
+        let samples: Vec<f32> = (0..4_800)
+            .map(|_| osc.process(480.0, SAMPLE_RATE))
+            .collect();
+
+        assert!(mean(&samples).abs() < 1e-3);
+    }
+
+    #[test]
+    fn saw_and_triangle_span_full_scale_at_the_right_frequency() {
+        for shape in [Shape::Saw, Shape::Triangle] {
+            let mut osc = DigitalOscillator::new(shape);
+            let samples: Vec<f32> = (0..12_800)
+                .map(|_| osc.process(375.0, SAMPLE_RATE))
+                .collect();
+
+            let max = samples.iter().copied().fold(f32::MIN, f32::max);
+            let min = samples.iter().copied().fold(f32::MAX, f32::min);
+            assert!((0.98..=1.0).contains(&max));
+            assert!((-1.0..-0.98).contains(&min));
+
+            // Every rising zero crossing is one period of `SAMPLE_RATE / 375` samples after the last
+            let crossings: Vec<usize> = samples
+                .windows(2)
+                .enumerate()
+                .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+                .map(|(n, _)| n)
+                .collect();
+            assert!(crossings.len() >= 99);
+            assert!(crossings.windows(2).all(|pair| pair[1] - pair[0] == 128));
+        }
+    }
+}
@@ -1,6 +1,18 @@
 pub mod analog;
 pub mod digital;
 
+/// The highest and lowest frequencies the oscillators run at, as a fraction of the sample rate.
+/// Anything outside of this, like a heavily transposed note, is clamped instead of making the
+/// oscillators alias wildly, stall, or produce NaNs. The ceiling sits just below Nyquist, so every
+/// note that can be played in tune still is.
+pub const MAX_FREQUENCY: f32 = 0.45;
+pub const MIN_FREQUENCY: f32 = 0.000_001;
+
+/// The phase increment for a frequency in Hertz, clamped to the range the oscillators support.
+pub fn phase_delta(frequency: f32, sample_rate: f32) -> f32 {
+    (frequency / sample_rate).clamp(MIN_FREQUENCY, MAX_FREQUENCY)
+}
+
 // Ported from Mutable Instruments firmware
 pub fn this_blep_sample(t: f32) -> f32 {
     return 0.5 * t * t;
@@ -9,7 +21,7 @@ pub fn this_blep_sample(t: f32) -> f32 {
 pub fn next_blep_sample(t: f32) -> f32 {
     let t = 1.0 - t;
 
-    return 0.5 * t * t;
+    return -0.5 * t * t;
 }
 
 pub fn next_integrated_blep_sample(t: f32) -> f32 {
@@ -23,3 +35,44 @@ pub fn next_integrated_blep_sample(t: f32) -> f32 {
 pub fn this_integrated_blep_sample(t: f32) -> f32 {
     return next_integrated_blep_sample(1.0 - t);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phase_delta_only_clamps_near_nyquist() {
+        assert_eq!(phase_delta(12_000.0, 44_100.0), 12_000.0 / 44_100.0);
+        assert_eq!(phase_delta(19_000.0, 44_100.0), 19_000.0 / 44_100.0);
+        assert_eq!(phase_delta(30_000.0, 44_100.0), MAX_FREQUENCY);
+        assert_eq!(phase_delta(-100.0, 44_100.0), MIN_FREQUENCY);
+    }
+
+    #[test]
+    fn extreme_frequencies_stay_finite_and_bounded() {
+        for frequency in [
+            0.0,
+            10_000.0,
+            15_000.0,
+            20_000.0,
+            22_050.0,
+            40_000.0,
+            f32::MAX,
+        ] {
+            for morph in [0.0, 0.25, 0.5, 0.75, 1.0] {
+                let (pw, waveshape) = analog::morph_to_pw_and_waveshape(morph);
+                let mut oscillator = analog::VariableSawOscillator::default();
+                let peak = (0..4096)
+                    .map(|_| {
+                        oscillator.prepare(pw, waveshape, 1.0, frequency, 44_100.0);
+                        oscillator.process().abs()
+                    })
+                    .fold(0.0, f32::max);
+                assert!(
+                    peak.is_finite() && peak < 4.0,
+                    "{frequency} Hz at morph {morph} peaked at {peak}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::sync::atomic::{self, AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// The number of samples in a single capture.
+pub const CAPTURE_LEN: usize = 1024;
+
+/// The latest capture of the output, shared between the audio thread and the editor. Both sides
+/// only ever touch atomics, so neither can block the other.
+pub struct ScopeBuffer {
+    /// Set by the editor while it's showing the scope. Nothing is captured otherwise.
+    pub enabled: AtomicBool,
+    /// Starts captures at note ons instead of at rising zero crossings.
+    pub trigger_on_note: AtomicBool,
+
+    /// The captured samples, stored as their bit patterns.
+    samples: Box<[AtomicU32]>,
+    /// Odd while a capture is being published and even once it's complete, so a reader can tell
+    /// when it raced with the audio thread and when there's a new capture.
+    sequence: AtomicU32,
+}
+
+impl Default for ScopeBuffer {
+    fn default() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            trigger_on_note: AtomicBool::new(false),
+
+            samples: (0..CAPTURE_LEN).map(|_| AtomicU32::new(0)).collect(),
+            sequence: AtomicU32::new(0),
+        }
+    }
+}
+
+impl ScopeBuffer {
+    /// Copy the latest capture into `out`, which should be `CAPTURE_LEN` samples long. Returns the
+    /// capture's sequence number, or `None` if the audio thread was publishing a new capture in
+    /// the meantime and `out` may be torn.
+    pub fn read(&self, out: &mut [f32]) -> Option<u32> {
+        let sequence = self.sequence.load(Ordering::Acquire);
+        if sequence % 2 == 1 {
+            return None;
+        }
+
+        for (sample, value) in self.samples.iter().zip(out.iter_mut()) {
+            *value = f32::from_bits(sample.load(Ordering::Relaxed));
+        }
+
+        atomic::fence(Ordering::Acquire);
+        if self.sequence.load(Ordering::Relaxed) == sequence {
+            Some(sequence)
+        } else {
+            None
+        }
+    }
+
+    fn publish(&self, capture: &[f32]) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+
+        for (sample, value) in self.samples.iter().zip(capture) {
+            sample.store(value.to_bits(), Ordering::Relaxed);
+        }
+
+        self.sequence.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Captures the output into a `ScopeBuffer`. Every capture starts at a trigger, so consecutive
+/// captures line up and the editor can draw a steady waveform.
+pub struct Scope {
+    pub buffer: Arc<ScopeBuffer>,
+
+    /// The capture in progress. It's only published once it's complete, so the editor never has
+    /// to wait for the audio thread to fill it.
+    capture: Vec<f32>,
+    /// The number of samples captured so far, or `None` while waiting for a trigger.
+    capture_pos: Option<usize>,
+    previous_sample: f32,
+    note_triggered: bool,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Self {
+            buffer: Arc::new(ScopeBuffer::default()),
+
+            capture: vec![0.0; CAPTURE_LEN],
+            capture_pos: None,
+            previous_sample: 0.0,
+            note_triggered: false,
+        }
+    }
+}
+
+impl Scope {
+    pub fn is_enabled(&self) -> bool {
+        self.buffer.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Drop any capture in progress and wait for the next trigger.
+    pub fn reset(&mut self) {
+        self.capture_pos = None;
+        self.previous_sample = 0.0;
+        self.note_triggered = false;
+    }
+
+    /// Lets the next sample start a capture when captures are triggered by notes.
+    pub fn note_on(&mut self) {
+        self.note_triggered = true;
+    }
+
+    pub fn process(&mut self, sample: f32) {
+        let triggered = if self.buffer.trigger_on_note.load(Ordering::Relaxed) {
+            self.note_triggered
+        } else {
+            self.previous_sample < 0.0 && sample >= 0.0
+        };
+        self.note_triggered = false;
+        self.previous_sample = sample;
+
+        let pos = match self.capture_pos {
+            Some(pos) => pos,
+            None if triggered => 0,
+            None => return,
+        };
+
+        self.capture[pos] = sample;
+        self.capture_pos = if pos + 1 == CAPTURE_LEN {
+            self.buffer.publish(&self.capture);
+            None
+        } else {
+            Some(pos + 1)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_scope(trigger_on_note: bool) -> Scope {
+        let scope = Scope::default();
+        scope.buffer.enabled.store(true, Ordering::Relaxed);
+        scope
+            .buffer
+            .trigger_on_note
+            .store(trigger_on_note, Ordering::Relaxed);
+
+        scope
+    }
+
+    fn read_capture(scope: &Scope) -> Vec<f32> {
+        let mut capture = vec![0.0; CAPTURE_LEN];
+        assert_eq!(scope.buffer.read(&mut capture), Some(2));
+
+        capture
+    }
+
+    #[test]
+    fn capture_starts_at_a_rising_zero_crossing() {
+        let mut scope = enabled_scope(false);
+        // A sine that starts on its way down, so the first rising zero crossing is at sample 75
+        let input: Vec<f32> = (0..2000)
+            .map(|n| ((n as f32 + 25.0) / 100.0 * std::f32::consts::TAU).sin())
+            .collect();
+        for &sample in &input {
+            scope.process(sample);
+        }
+
+        assert_eq!(read_capture(&scope), input[75..75 + CAPTURE_LEN]);
+    }
+
+    #[test]
+    fn capture_starts_at_the_note_on() {
+        let mut scope = enabled_scope(true);
+        let input: Vec<f32> = (0..2000).map(|n| n as f32).collect();
+        for (n, &sample) in input.iter().enumerate() {
+            if n == 300 {
+                scope.note_on();
+            }
+            scope.process(sample);
+        }
+
+        assert_eq!(read_capture(&scope), input[300..300 + CAPTURE_LEN]);
+    }
+}
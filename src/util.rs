@@ -0,0 +1,110 @@
+//! Small DSP helpers. nih-plug's own utilities are re-exported here so `util::` covers both.
+
+pub use nih_plug::util::*;
+
+/// A soft limiter that leaves anything within `[-1, 1]` untouched and smoothly saturates samples
+/// beyond that towards ±2, instead of letting them clip hard further down the line.
+pub fn soft_limit(x: f32) -> f32 {
+    let magnitude = x.abs();
+    if magnitude <= 1.0 {
+        x
+    } else {
+        x.signum() * (1.0 + (magnitude - 1.0).tanh())
+    }
+}
+
+/// Apply `gain` to `x`, where anything above unity gain is an overshoot that's saturated into the
+/// headroom the rest of the signal leaves below full scale. The overshoot can fill up that
+/// headroom but never push the signal past ±1, and at or below unity gain this is just `x * gain`.
+pub fn saturate_overshoot(x: f32, gain: f32) -> f32 {
+    let base = x * gain.min(1.0);
+    let overshoot = x * (gain - 1.0).max(0.0);
+    let headroom = 1.0 - base.abs();
+    if overshoot == 0.0 || headroom <= 0.0 {
+        base
+    } else {
+        base + headroom * (overshoot / headroom).tanh()
+    }
+}
+
+/// A tiny, allocation free pseudo random number generator (a 32-bit LCG). Every randomized feature
+/// gets its own `Rng` seeded from Toby's seed, so renders are reproducible.
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state = self
+            .state
+            .wrapping_mul(1_664_525)
+            .wrapping_add(1_013_904_223);
+
+        self.state
+    }
+
+    /// A uniformly distributed value in `[0, 1)`.
+    pub fn next_unipolar(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+
+    /// A uniformly distributed value in `[-1, 1)`, for white noise.
+    pub fn next_bipolar(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+}
+
+/// Derive the seed for one of several independent random streams from a single base seed. Seeding
+/// every `Rng` with the same seed, or with consecutive outputs of one `Rng`, would give them all
+/// the same sequence, so the base seed and the stream index are scrambled together instead.
+pub fn stream_seed(seed: u32, stream: u32) -> u32 {
+    let mut x = seed ^ stream.wrapping_add(1).wrapping_mul(0x9e37_79b9);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saturated_overshoot_stays_within_full_scale() {
+        for x in [-1.0, -0.5, 0.0, 0.25, 0.9, 1.0] {
+            assert_eq!(saturate_overshoot(x, 0.5), x * 0.5);
+            assert_eq!(saturate_overshoot(x, 1.0), x);
+
+            let mut previous = saturate_overshoot(x, 1.0).abs();
+            for gain in [1.01, 1.1, 1.5, 2.0, 10.0] {
+                let y = saturate_overshoot(x, gain);
+                assert!(y.abs() <= 1.0, "{x} at a gain of {gain} came out as {y}");
+                assert!(y.abs() >= previous);
+                previous = y.abs();
+            }
+        }
+    }
+
+    #[test]
+    fn streams_from_the_same_seed_are_independent() {
+        let mut streams: Vec<Rng> = (0..3).map(|i| Rng::new(stream_seed(1, i))).collect();
+        let first_values: Vec<Vec<u32>> = streams
+            .iter_mut()
+            .map(|rng| (0..8).map(|_| rng.next_u32()).collect())
+            .collect();
+
+        // No stream is the same as, or just a shifted copy of, another one
+        for (i, a) in first_values.iter().enumerate() {
+            for b in &first_values[i + 1..] {
+                assert!(a.iter().all(|x| !b.contains(x)));
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+/// The longest delay the widener supports. Much beyond this the delayed channel stops fusing with
+/// the other one and is heard as a separate echo.
+pub const MAX_DELAY_MS: f32 = 30.0;
+
+/// A Haas effect stereo widener. Delaying one channel by a few milliseconds makes a mono signal
+/// sound wide without the phase cancellation mid/side widening causes when summed back to mono.
+#[derive(Default)]
+pub struct HaasWidener {
+    /// The delay line. Allocated in `resize()` since its length depends on the sample rate.
+    buffer: Vec<f32>,
+    write_pos: usize,
+    /// The number of silent samples written in a row, to tell when the delay line is empty.
+    silent_samples: usize,
+}
+
+impl HaasWidener {
+    /// Allocate the delay line for `MAX_DELAY_MS` at this sample rate, plus the sample interpolated
+    /// towards at the longest delay. Should only be called from `initialize()`. If the length
+    /// doesn't change the delay line is kept as is, so reinitializing at the same sample rate
+    /// doesn't cut off what's still in it.
+    pub fn resize(&mut self, sample_rate: f32) {
+        let len = (MAX_DELAY_MS / 1000.0 * sample_rate).ceil() as usize + 2;
+        if len == self.buffer.len() {
+            return;
+        }
+
+        self.buffer = vec![0.0; len];
+        self.write_pos = 0;
+        self.silent_samples = len;
+    }
+
+    pub fn reset(&mut self) {
+        self.buffer.fill(0.0);
+        self.write_pos = 0;
+        self.silent_samples = self.buffer.len();
+    }
+
+    /// Whether the whole delay line is silent, so the widener would only output silence for a
+    /// silent input no matter what the delay is set to.
+    pub fn is_silent(&self) -> bool {
+        self.silent_samples >= self.buffer.len()
+    }
+
+    /// Write the input to the delay line and return it delayed by `delay_samples` samples. A delay
+    /// of zero passes the input straight through. Fractional delays are linearly interpolated, so
+    /// the delay can be swept smoothly without the read position jumping.
+    pub fn process(&mut self, input: f32, delay_samples: f32) -> f32 {
+        let len = self.buffer.len();
+        if len == 0 {
+            return input;
+        }
+
+        self.buffer[self.write_pos] = input;
+        self.silent_samples = if input == 0.0 {
+            self.silent_samples.saturating_add(1)
+        } else {
+            0
+        };
+        let delay_samples = delay_samples.clamp(0.0, (len - 2) as f32);
+        let whole_samples = delay_samples as usize;
+        let fraction = delay_samples - whole_samples as f32;
+        let read_pos = (self.write_pos + len - whole_samples) % len;
+        let previous_pos = (read_pos + len - 1) % len;
+        self.write_pos = (self.write_pos + 1) % len;
+
+        self.buffer[read_pos] + (self.buffer[previous_pos] - self.buffer[read_pos]) * fraction
+    }
+}